@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct Entry<T> {
+    snapshot: Option<(Instant, Arc<T>)>,
+    invalidated_at: Option<Instant>,
+}
+
+/// Short-lived per-token snapshots. A snapshot is stamped with the time its
+/// read started, so a read racing a write can't store data older than the
+/// write's invalidation.
+pub struct SnapshotCache<T> {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry<T>>>,
+}
+
+impl<T> SnapshotCache<T> {
+    pub fn new(ttl: Duration) -> SnapshotCache<T> {
+        SnapshotCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, token: &str) -> Option<Arc<T>> {
+        let entries = self.entries.lock().unwrap();
+
+        match &entries.get(token)?.snapshot {
+            Some((started, snapshot)) if started.elapsed() < self.ttl => Some(snapshot.clone()),
+            _ => None,
+        }
+    }
+
+    /// Stores a snapshot read since `started`, unless the token was written to meanwhile.
+    pub fn put(&self, token: &str, started: Instant, snapshot: T) -> Arc<T> {
+        let snapshot = Arc::new(snapshot);
+
+        if self.ttl.is_zero() {
+            return snapshot;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+
+        entries.retain(|_, e| {
+            e.snapshot.as_ref().is_some_and(|(s, _)| s.elapsed() < ttl)
+                || e.invalidated_at.is_some_and(|i| i.elapsed() < ttl)
+        });
+
+        let entry = entries.entry(token.to_owned()).or_insert(Entry {
+            snapshot: None,
+            invalidated_at: None,
+        });
+
+        if entry.invalidated_at.is_none_or(|i| i < started) {
+            entry.snapshot = Some((started, snapshot.clone()));
+        }
+
+        snapshot
+    }
+
+    pub fn invalidate(&self, token: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        self.entries.lock().unwrap().insert(
+            token.to_owned(),
+            Entry {
+                snapshot: None,
+                invalidated_at: Some(Instant::now()),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::cache::SnapshotCache;
+
+    #[test]
+    fn write_invalidates() {
+        let cache = SnapshotCache::new(Duration::from_secs(60));
+
+        cache.put("tok", Instant::now(), 1);
+        assert_eq!(cache.get("tok").as_deref(), Some(&1));
+        assert_eq!(cache.get("other"), None);
+
+        cache.invalidate("tok");
+        assert_eq!(cache.get("tok"), None);
+    }
+
+    #[test]
+    fn reads_racing_a_write_are_not_stored() {
+        let cache = SnapshotCache::new(Duration::from_secs(60));
+        cache.put("tok", Instant::now(), 1);
+
+        let started = Instant::now();
+        cache.invalidate("tok");
+        cache.put("tok", started, 2);
+        assert_eq!(cache.get("tok"), None);
+
+        let started = Instant::now();
+        cache.invalidate("fresh");
+        cache.put("fresh", started, 2);
+        assert_eq!(cache.get("fresh"), None);
+
+        cache.put("tok", Instant::now(), 3);
+        assert_eq!(cache.get("tok").as_deref(), Some(&3));
+    }
+
+    #[test]
+    fn zero_ttl_disables() {
+        let cache = SnapshotCache::new(Duration::ZERO);
+
+        cache.put("tok", Instant::now(), 1);
+        assert_eq!(cache.get("tok"), None);
+    }
+}
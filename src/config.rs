@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::store::{check_color, check_token, parse_precision, EXPIRE_SECONDS, MAX_TTL};
+
+/// Rows an HTML /see draws unless `PCAFE_MAX_ROWS` says otherwise.
+pub const DEFAULT_MAX_ROWS: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+    Light,
+    Dark,
+    Auto,
+}
+
+impl std::str::FromStr for Theme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Theme> {
+        match s {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            "auto" => Ok(Theme::Auto),
+            _ => Err(anyhow!("theme must be light, dark or auto")),
+        }
+    }
+}
+
+/// How /see draws the bar of a key that has no max, `PCAFE_MISSING_MAX`. A
+/// key without a max has no percent either way, see `Value::percent`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingMax {
+    /// Its current out of 100.
+    Hundred,
+    /// A spinning bar, like a max of 0.
+    Indeterminate,
+}
+
+impl std::str::FromStr for MissingMax {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<MissingMax> {
+        match s {
+            "hundred" => Ok(MissingMax::Hundred),
+            "indeterminate" => Ok(MissingMax::Indeterminate),
+            _ => Err(anyhow!("missing max must be hundred or indeterminate")),
+        }
+    }
+}
+
+/// The settings that can change while running, see `Config::load`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub expire_seconds: usize,
+    pub theme: Theme,
+    pub theme_color: Option<String>,
+    /// Decimals of the percents shown, `?precision=` overrides it.
+    pub percent_precision: u8,
+    /// Shown on /see in place of a missing state, `?` unless set. Only the
+    /// HTML uses it, the JSON keeps such states `null`.
+    pub default_state: String,
+    pub missing_max: MissingMax,
+    /// Rows an HTML /see draws before it stops with a note of how many more
+    /// there are, `?limit=` overrides it. The other formats have them all.
+    pub max_rows: usize,
+    /// Names whose /see is the keys of several tokens at once, see
+    /// `parse_aliases`.
+    pub aliases: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+    /// Reads the settings from the environment, with `PCAFE_CONFIG` naming an
+    /// optional file of `NAME=value` lines that take precedence. The
+    /// environment can't change under a running process but the file can, so
+    /// that's what a SIGHUP reload picks up.
+    pub fn load() -> Result<Config> {
+        let file = match std::env::var("PCAFE_CONFIG") {
+            Ok(path) => std::fs::read_to_string(&path)
+                .with_context(|| format!("can't read PCAFE_CONFIG {}", path))?,
+            Err(_) => String::new(),
+        };
+
+        Config::from_lookup(parse_config_file(&file), |name| std::env::var(name).ok())
+    }
+
+    fn from_lookup(
+        file: HashMap<String, String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config> {
+        let get = |name: &str| file.get(name).cloned().or_else(|| env(name));
+
+        let expire_seconds = match get("PCAFE_EXPIRE_SECONDS") {
+            Some(s) => match s.trim().parse() {
+                Ok(secs) if secs > 0 && secs <= MAX_TTL => secs,
+                _ => return Err(anyhow!("bad PCAFE_EXPIRE_SECONDS {:?}", s)),
+            },
+            None => EXPIRE_SECONDS,
+        };
+
+        let theme = match get("PCAFE_THEME") {
+            Some(t) => t.trim().parse().context("bad PCAFE_THEME")?,
+            None => Theme::Light,
+        };
+
+        let theme_color = get("PCAFE_THEME_COLOR");
+
+        if let Some(c) = &theme_color {
+            check_color(c).context("bad PCAFE_THEME_COLOR")?;
+        }
+
+        let percent_precision = match get("PCAFE_PERCENT_PRECISION") {
+            Some(p) => parse_precision(&p).context("bad PCAFE_PERCENT_PRECISION")?,
+            None => 0,
+        };
+
+        let default_state = match get("PCAFE_DEFAULT_STATE") {
+            Some(s) if !s.trim().is_empty() => s.trim().to_owned(),
+            _ => "?".to_owned(),
+        };
+
+        let missing_max = match get("PCAFE_MISSING_MAX") {
+            Some(m) => m.trim().parse().context("bad PCAFE_MISSING_MAX")?,
+            None => MissingMax::Hundred,
+        };
+
+        let max_rows = match get("PCAFE_MAX_ROWS") {
+            Some(s) => match s.trim().parse() {
+                Ok(0) | Err(_) => return Err(anyhow!("bad PCAFE_MAX_ROWS {:?}", s)),
+                Ok(rows) => rows,
+            },
+            None => DEFAULT_MAX_ROWS,
+        };
+
+        let aliases = match get("PCAFE_ALIASES") {
+            Some(a) => parse_aliases(&a).context("bad PCAFE_ALIASES")?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(Config {
+            expire_seconds,
+            theme,
+            theme_color,
+            percent_precision,
+            default_state,
+            missing_max,
+            max_rows,
+            aliases,
+        })
+    }
+}
+
+/// `PCAFE_ALIASES`, `name=token,token;name=token,...`. A name is spelled like
+/// a token and has at least one token that isn't itself.
+fn parse_aliases(s: &str) -> Result<BTreeMap<String, Vec<String>>> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(|alias| {
+            let (name, tokens) = alias
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{:?} isn't name=token,token", alias))?;
+            let name = check_token(name.trim())?;
+            let tokens = tokens
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(|t| match check_token(t)? {
+                    t if t == name => Err(anyhow!("alias {} can't include itself", name)),
+                    t => Ok(t.to_owned()),
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            if tokens.is_empty() {
+                return Err(anyhow!("alias {} has no tokens", name));
+            }
+
+            Ok((name.to_owned(), tokens))
+        })
+        .collect()
+}
+
+fn parse_config_file(file: &str) -> HashMap<String, String> {
+    file.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use std::collections::BTreeMap;
+
+    use crate::config::{
+        parse_aliases, parse_config_file, Config, MissingMax, Theme, DEFAULT_MAX_ROWS,
+    };
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "PCAFE_EXPIRE_SECONDS" => Some("60".to_owned()),
+            "PCAFE_THEME" => Some("dark".to_owned()),
+            _ => None,
+        }
+    }
+
+    fn config(file: &str) -> Result<Config> {
+        Config::from_lookup(parse_config_file(file), env)
+    }
+
+    #[test]
+    fn file_overrides_env() -> Result<()> {
+        assert_eq!(
+            config("# reloaded on SIGHUP\nPCAFE_THEME = auto\n\n")?,
+            Config {
+                expire_seconds: 60,
+                theme: Theme::Auto,
+                theme_color: None,
+                percent_precision: 0,
+                default_state: "?".to_owned(),
+                missing_max: MissingMax::Hundred,
+                max_rows: DEFAULT_MAX_ROWS,
+                aliases: BTreeMap::new(),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn theme_colors_are_checked() {
+        assert!(config("PCAFE_THEME_COLOR=red;}").is_err());
+    }
+
+    #[test]
+    fn expire_seconds_are_bounded() {
+        assert!(config("PCAFE_EXPIRE_SECONDS=0").is_err());
+        assert!(config("PCAFE_EXPIRE_SECONDS=999999999").is_err());
+    }
+
+    #[test]
+    fn percent_precision() -> Result<()> {
+        assert_eq!(config("PCAFE_PERCENT_PRECISION=2")?.percent_precision, 2);
+        assert!(config("PCAFE_PERCENT_PRECISION=4").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_state_is_trimmed() -> Result<()> {
+        assert_eq!(
+            config("PCAFE_DEFAULT_STATE= starting")?.default_state,
+            "starting"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_max() -> Result<()> {
+        assert_eq!(
+            config("PCAFE_MISSING_MAX=indeterminate")?.missing_max,
+            MissingMax::Indeterminate
+        );
+        assert!(config("PCAFE_MISSING_MAX=0").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_rows() -> Result<()> {
+        assert_eq!(config("PCAFE_MAX_ROWS=50")?.max_rows, 50);
+        assert!(config("PCAFE_MAX_ROWS=0").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn aliases() -> Result<()> {
+        assert_eq!(
+            config("PCAFE_ALIASES=daily=team-a, team-b; infra=ops")?.aliases,
+            BTreeMap::from([
+                (
+                    "daily".to_owned(),
+                    vec!["team-a".to_owned(), "team-b".to_owned()]
+                ),
+                ("infra".to_owned(), vec!["ops".to_owned()]),
+            ])
+        );
+        for bad in ["daily", "daily=", "daily=a,daily", "dai/ly=a", "daily=a b"] {
+            assert!(parse_aliases(bad).is_err(), "{}", bad);
+        }
+
+        Ok(())
+    }
+}
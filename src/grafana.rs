@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::store::HistoryPoint;
+
+/// `POST .../search` of the Grafana JSON API datasource. `target` is what's
+/// typed into the metric picker so far.
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchRequest {
+    #[serde(default)]
+    pub target: String,
+}
+
+/// `POST .../query`, with `range.from` and `range.to` as RFC 3339 UTC times.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: Option<Range>,
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    pub max_data_points: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Range {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub hide: bool,
+}
+
+/// One target's points, each `[current, unix milliseconds]`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Series {
+    pub target: String,
+    pub datapoints: Vec<(i64, i64)>,
+}
+
+/// A metric is `token:key`. Tokens can't have a `:`, keys can.
+pub fn split_target(target: &str) -> Option<(&str, &str)> {
+    target.split_once(':')
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Unix seconds of `2024-05-01T12:30:00.000Z`, the form Grafana sends its
+/// range in. Fractions of a second are dropped.
+pub fn parse_time(s: &str) -> Result<i64> {
+    let bad = || anyhow!("bad time {:?}, expected YYYY-MM-DDTHH:MM:SSZ", s);
+
+    let rest = s.strip_suffix('Z').ok_or_else(bad)?;
+    let rest = rest.split_once('.').map_or(rest, |(whole, _)| whole);
+    let (date, time) = rest.split_once('T').ok_or_else(bad)?;
+
+    let numbers = |part: &str, sep: char| -> Result<Vec<i64>> {
+        part.split(sep)
+            .map(|n| n.parse::<i64>().map_err(|_| bad()))
+            .collect()
+    };
+
+    match (&numbers(date, '-')?[..], &numbers(time, ':')?[..]) {
+        (&[year, month @ 1..=12, day @ 1..=31], &[hour @ 0..=23, min @ 0..=59, sec @ 0..=60]) => {
+            Ok(days_from_civil(year, month, day) * 86_400 + hour * 3600 + min * 60 + sec)
+        }
+        _ => Err(bad()),
+    }
+}
+
+/// `points` of `target` within `range`, inclusive, keeping the latest
+/// `max_points` of them.
+pub fn series(
+    target: &str,
+    points: &[HistoryPoint],
+    range: Option<(i64, i64)>,
+    max_points: Option<usize>,
+) -> Series {
+    let mut datapoints = points
+        .iter()
+        .filter(|p| range.is_none_or(|(from, to)| p.t >= from && p.t <= to))
+        .map(|p| (p.current, p.t * 1000))
+        .collect::<Vec<_>>();
+
+    if let Some(max) = max_points {
+        datapoints.drain(..datapoints.len().saturating_sub(max));
+    }
+
+    Series {
+        target: target.to_owned(),
+        datapoints,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{
+        grafana::{parse_time, series, split_target, QueryRequest, Series},
+        store::HistoryPoint,
+    };
+
+    #[test]
+    fn times_and_series() -> Result<()> {
+        assert_eq!(parse_time("1970-01-01T00:00:00Z")?, 0);
+        assert_eq!(parse_time("2016-10-31T06:33:44.866Z")?, 1_477_895_624);
+        assert_eq!(parse_time("2024-02-29T23:59:59Z")?, 1_709_251_199);
+        assert!(parse_time("2016-10-31 06:33:44").is_err());
+        assert!(parse_time("2016-13-31T06:33:44Z").is_err());
+
+        assert_eq!(split_target("tok:build:eu"), Some(("tok", "build:eu")));
+        assert_eq!(split_target("tok"), None);
+
+        let points = [10, 20, 30, 40].map(|t| HistoryPoint { t, current: t / 10 });
+        assert_eq!(
+            series("tok:build", &points, Some((20, 40)), Some(2)),
+            Series {
+                target: "tok:build".to_owned(),
+                datapoints: vec![(3, 30_000), (4, 40_000)],
+            }
+        );
+        assert_eq!(series("tok:build", &points, None, None).datapoints.len(), 4);
+
+        let query: QueryRequest = serde_json::from_str(
+            r#"{
+                "range": {"from": "2016-10-31T06:33:44.866Z", "to": "2016-10-31T12:33:44.866Z"},
+                "targets": [{"target": "tok:build", "refId": "A", "type": "timeserie"}],
+                "maxDataPoints": 550
+            }"#,
+        )?;
+        assert_eq!(query.targets[0].target, "tok:build");
+        assert_eq!(query.max_data_points, Some(550));
+        assert_eq!(
+            serde_json::to_string(&series("t:k", &points[..1], None, None))?,
+            r#"{"target":"t:k","datapoints":[[1,10000]]}"#
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use redis::{aio::ConnectionLike, Cmd, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+
+const WINDOW: usize = 1000;
+
+/// Durations of the last `WINDOW` redis round trips.
+#[derive(Default)]
+pub struct Latency {
+    recent: Mutex<VecDeque<Duration>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LatencySummary {
+    pub avg_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Latency {
+    pub fn record(&self, d: Duration) {
+        let mut recent = self.recent.lock().unwrap();
+
+        if recent.len() == WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(d);
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut sorted = Vec::from_iter(self.recent.lock().unwrap().iter().copied());
+
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort();
+
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let p99 = sorted[(sorted.len() * 99).div_ceil(100) - 1];
+
+        Some(LatencySummary {
+            avg_ms: sorted.iter().copied().map(ms).sum::<f64>() / sorted.len() as f64,
+            p99_ms: ms(p99),
+        })
+    }
+}
+
+/// A connection that records how long each command or pipeline took, and
+/// optionally gives up on the ones that take longer than a timeout.
+#[derive(Clone)]
+pub struct Timed<C> {
+    inner: C,
+    latency: Arc<Latency>,
+    response_timeout: Option<Duration>,
+}
+
+impl<C> Timed<C> {
+    pub fn new(inner: C, latency: Arc<Latency>) -> Timed<C> {
+        Timed {
+            inner,
+            latency,
+            response_timeout: None,
+        }
+    }
+
+    pub fn with_response_timeout(self, response_timeout: Option<Duration>) -> Timed<C> {
+        Timed {
+            response_timeout,
+            ..self
+        }
+    }
+}
+
+async fn within<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = RedisResult<T>>,
+) -> RedisResult<T> {
+    let Some(timeout) = timeout else {
+        return fut.await;
+    };
+
+    tokio::time::timeout(timeout, fut)
+        .await
+        .unwrap_or_else(|_| {
+            Err(RedisError::from(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no response from redis in {:?}", timeout),
+            )))
+        })
+}
+
+impl<C: ConnectionLike + Send> ConnectionLike for Timed<C> {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let res = within(self.response_timeout, self.inner.req_packed_command(cmd)).await;
+            self.latency.record(started.elapsed());
+
+            res
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let res = within(
+                self.response_timeout,
+                self.inner.req_packed_commands(cmd, offset, count),
+            )
+            .await;
+            self.latency.record(started.elapsed());
+
+            res
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::latency::{Latency, LatencySummary};
+
+    #[test]
+    fn summary_of_the_window() {
+        let latency = Latency::default();
+        assert_eq!(latency.summary(), None);
+
+        for ms in 1..=100 {
+            latency.record(Duration::from_millis(ms));
+        }
+        let LatencySummary { avg_ms, p99_ms } = latency.summary().unwrap();
+        assert!((avg_ms - 50.5).abs() < 1e-9, "{}", avg_ms);
+        assert!((p99_ms - 99.0).abs() < 1e-9, "{}", p99_ms);
+
+        for _ in 0..1000 {
+            latency.record(Duration::from_millis(2));
+        }
+        assert!((latency.summary().unwrap().p99_ms - 2.0).abs() < 1e-9);
+    }
+}
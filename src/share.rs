@@ -0,0 +1,227 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Signs read-only links to a token's /see page, `/shared/{sig}/{sealed}`.
+/// `sig` is the expiry and an HMAC over the token and the expiry, `sealed` is
+/// a random nonce and the token XORed with an HMAC keystream of that nonce,
+/// so a link can be handed out without the token it reads from.
+#[derive(Clone)]
+pub struct ShareKey {
+    secret: Arc<Vec<u8>>,
+}
+
+/// What view tokens start with, see `ShareKey::view_token`. No token that
+/// does can be written to, see the README for tokens named so before.
+pub const VIEW_PREFIX: &str = "view-";
+
+/// Bytes of randomness at the start of a link's `sealed`.
+const NONCE_LEN: usize = 16;
+
+pub fn is_view_token(token: &str) -> bool {
+    token.starts_with(VIEW_PREFIX)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(anyhow!("bad link"));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| anyhow!("bad link")))
+        .collect()
+}
+
+impl ShareKey {
+    pub fn new(secret: &str) -> ShareKey {
+        ShareKey {
+            secret: Arc::new(secret.as_bytes().to_vec()),
+        }
+    }
+
+    fn mac(&self, purpose: &str, parts: &[&[u8]]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(purpose.as_bytes());
+
+        for part in parts {
+            mac.update(b":");
+            mac.update(part);
+        }
+
+        mac
+    }
+
+    /// The token XORed with HMAC blocks for the link's nonce, either way
+    /// round. A pad of the expiry alone, which anyone can pick and read off a
+    /// link, would be shared by links that XOR to each other's tokens.
+    fn xor_keystream(&self, data: &[u8], nonce: &[u8]) -> Vec<u8> {
+        data.chunks(32)
+            .enumerate()
+            .flat_map(|(block, chunk)| {
+                let pad = self
+                    .mac("seal", &[nonce, &block.to_be_bytes()])
+                    .finalize()
+                    .into_bytes();
+
+                chunk
+                    .iter()
+                    .zip(pad)
+                    .map(|(b, p)| b ^ p)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// The `{sig}/{sealed}` part of a link to `token` that works until `expires`.
+    pub fn sign(&self, token: &str, expires: i64) -> String {
+        let sig = self
+            .mac("sign", &[token.as_bytes(), &expires.to_be_bytes()])
+            .finalize()
+            .into_bytes();
+
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).expect("the OS has randomness");
+
+        format!(
+            "{}-{}/{}{}",
+            expires,
+            hex(&sig),
+            hex(&nonce),
+            hex(&self.xor_keystream(token.as_bytes(), &nonce))
+        )
+    }
+
+    /// A token that `/see` reads `token` by and that can't send, the same for
+    /// as long as the secret is. It's a cut HMAC, so it doesn't give the
+    /// token away; `/see` finds the token by the mapping that
+    /// `Store::remember_view` keeps.
+    pub fn view_token(&self, token: &str) -> String {
+        let mac = self
+            .mac("view", &[token.as_bytes()])
+            .finalize()
+            .into_bytes();
+
+        format!("{}{}", VIEW_PREFIX, hex(&mac[..12]))
+    }
+
+    /// The token a link's `sig` and `sealed` segments stand for, if it was
+    /// signed with this key and hasn't expired by `now`.
+    pub fn open(&self, sig: &str, sealed: &str, now: i64) -> Result<String> {
+        let (expires, sig) = sig.split_once('-').ok_or_else(|| anyhow!("bad link"))?;
+        let expires: i64 = expires.parse().map_err(|_| anyhow!("bad link"))?;
+
+        let sealed = unhex(sealed)?;
+        if sealed.len() <= NONCE_LEN {
+            return Err(anyhow!("bad link"));
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+
+        let token = String::from_utf8(self.xor_keystream(sealed, nonce))
+            .map_err(|_| anyhow!("bad link"))?;
+
+        self.mac("sign", &[token.as_bytes(), &expires.to_be_bytes()])
+            .verify_slice(&unhex(sig)?)
+            .map_err(|_| anyhow!("bad link"))?;
+
+        if now >= expires {
+            return Err(anyhow!("link expired"));
+        }
+
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{
+        share::{is_view_token, unhex, ShareKey, NONCE_LEN},
+        store::check_token,
+    };
+
+    #[test]
+    fn signed_links() -> Result<()> {
+        let key = ShareKey::new("secret");
+        let link = key.sign("my-token", 1000);
+        let (sig, sealed) = link.split_once('/').unwrap();
+
+        assert!(!link.contains("my-token"), "{}", link);
+        assert_eq!(key.open(sig, sealed, 999)?, "my-token");
+
+        assert!(key
+            .open(sig, sealed, 1000)
+            .unwrap_err()
+            .to_string()
+            .contains("expired"));
+        assert!(ShareKey::new("other").open(sig, sealed, 999).is_err());
+
+        let later = sig.replacen("1000-", "9000-", 1);
+        assert!(key.open(&later, sealed, 999).is_err());
+
+        let mut tampered = sealed.to_owned();
+        tampered.replace_range(0..2, if &sealed[0..2] == "00" { "01" } else { "00" });
+        assert!(key.open(sig, &tampered, 999).is_err());
+
+        for junk in ["", "z", "abc"] {
+            assert!(key.open(sig, junk, 999).is_err());
+            assert!(key.open(junk, sealed, 999).is_err());
+        }
+
+        // Just the nonce
+        assert!(key.open(sig, &sealed[..NONCE_LEN * 2], 999).is_err());
+
+        let long = "t".repeat(100);
+        let link = key.sign(&long, 1000);
+        let (sig, sealed) = link.split_once('/').unwrap();
+        assert_eq!(key.open(sig, sealed, 0)?, long);
+
+        Ok(())
+    }
+
+    #[test]
+    fn links_dont_share_pads() -> Result<()> {
+        let key = ShareKey::new("secret");
+        let sealed = |token: &str| {
+            let link = key.sign(token, 1000);
+            let (_, sealed) = link.split_once('/').unwrap();
+
+            unhex(sealed).unwrap()
+        };
+        let xor = |a: &[u8], b: &[u8]| a.iter().zip(b).map(|(a, b)| a ^ b).collect::<Vec<_>>();
+
+        // With one pad per expiry, a link of one's own token gave away any
+        // other link's token of the same expiry
+        let (mine, theirs) = (sealed("mine-tok"), sealed("their-tk"));
+        assert_ne!(
+            xor(&mine[NONCE_LEN..], &theirs[NONCE_LEN..]),
+            xor(b"mine-tok", b"their-tk")
+        );
+        assert_ne!(sealed("mine-tok"), mine);
+
+        Ok(())
+    }
+
+    #[test]
+    fn view_tokens() -> Result<()> {
+        let key = ShareKey::new("secret");
+        let view = key.view_token("my-token");
+
+        assert_eq!(view, key.view_token("my-token"));
+        assert_ne!(view, key.view_token("my-token2"));
+        assert_ne!(view, ShareKey::new("other").view_token("my-token"));
+        assert!(is_view_token(&view) && !is_view_token("my-token"));
+        assert_eq!(view.len(), "view-".len() + 24);
+        check_token(&view)?;
+
+        Ok(())
+    }
+}
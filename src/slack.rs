@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde_json::json;
+
+/// A Slack incoming webhook, `PCAFE_SLACK_WEBHOOK`, that's told when a token
+/// completes.
+#[derive(Clone)]
+pub struct SlackWebhook {
+    url: Arc<Uri>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl SlackWebhook {
+    pub fn new(url: &str) -> Result<SlackWebhook> {
+        let url: Uri = url
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("bad PCAFE_SLACK_WEBHOOK"))?;
+
+        if url.scheme_str() != Some("https") {
+            return Err(anyhow!("PCAFE_SLACK_WEBHOOK must be an https URL"));
+        }
+
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_only()
+            .enable_http1()
+            .build();
+
+        Ok(SlackWebhook {
+            url: Arc::new(url),
+            client: Client::builder().build(https),
+        })
+    }
+
+    /// Posts `text` as a plain message.
+    pub async fn post(&self, text: &str) -> Result<()> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_ref().clone())
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "text": text }).to_string()))?;
+
+        let res = self.client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(anyhow!("Slack answered {}", res.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::slack::SlackWebhook;
+
+    #[test]
+    fn webhooks_are_https() {
+        assert!(SlackWebhook::new("https://hooks.slack.com/services/T0/B0/x").is_ok());
+        assert!(SlackWebhook::new("http://hooks.slack.com/services/T0/B0/x").is_err());
+        assert!(SlackWebhook::new("not a url").is_err());
+    }
+}
@@ -1,13 +1,67 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::future::Future;
+use std::num::IntErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
-use futures::{Future, StreamExt};
-use redis::{aio::ConnectionManager, Cmd, FromRedisValue, ToRedisArgs};
-use redis::{AsyncCommands, RedisResult};
+use redis::AsyncCommands;
+use redis::{Cmd, ToRedisArgs};
+use serde::{Deserialize, Serialize};
 
-const EXPIRE_SECONDS: usize = 60 * 60 * 4;
+pub const EXPIRE_SECONDS: usize = 60 * 60 * 4;
+/// The longest TTL a key can be given, a year, by `PCAFE_EXPIRE_SECONDS` or
+/// a send's `_ttl=`.
+pub const MAX_TTL: usize = 365 * 86_400;
+const MAX_ERROR_LEN: usize = 512;
+const MAX_NOTE_LEN: usize = 512;
+const MAX_TAGS: usize = 16;
+const MAX_TITLE_LEN: usize = 200;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const HISTORY_LEN: usize = 60;
+/// The latest points of a key's history its rate is taken over, see
+/// `rate_per_sec`.
+const RATE_POINTS: usize = 3;
+/// Completion events kept per token, see `Store::get_events`.
+const EVENTS_LEN: usize = 1000;
+/// How long a send's `Idempotency-Key` is remembered, see `Store::claim_send`.
+pub const IDEMPOTENCY_SECONDS: usize = 60 * 60;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+const MAX_WEIGHT: f64 = 1e6;
 
+/// Names must be non-empty, see `check_string_or_empty` for
+/// the places where a blank value is legitimate.
 pub fn check_string(s: &str) -> Result<&str> {
+    if s.is_empty() {
+        return Err(anyhow!("must not be empty"));
+    }
+
+    check_string_or_empty(s)
+}
+
+/// Tokens are capability secrets, so they keep to `STRING_REQ`. Keys under
+/// them are labels and get more room, see `check_key`.
+pub fn check_token(s: &str) -> Result<&str> {
+    check_string(s).map_err(|e| anyhow!("token {}", e))
+}
+
+/// Keys allow `:` on top of the token characters, for `group:rest` names.
+/// Glob characters like `*`, `?` and `[` stay out, a key must never widen the
+/// patterns it becomes part of.
+pub fn check_key(s: &str) -> Result<&str> {
+    let ok = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c));
+
+    if ok {
+        Ok(s)
+    } else {
+        Err(anyhow!("key must be non-empty {}", KEY_REQ))
+    }
+}
+
+pub fn check_string_or_empty(s: &str) -> Result<&str> {
     let r = s
         .chars()
         .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
@@ -19,17 +73,285 @@ pub fn check_string(s: &str) -> Result<&str> {
     }
 }
 
+const NAMED_COLORS: &[&str] = &[
+    "black", "blue", "gray", "green", "grey", "orange", "pink", "purple", "red", "teal", "white",
+    "yellow",
+];
+
+/// Colors end up in a style attribute, so only `#rgb`/`#rrggbb` or a small set
+/// of names are accepted.
+pub fn check_color(s: &str) -> Result<&str> {
+    let hex = s.strip_prefix('#').is_some_and(|h| {
+        (h.len() == 3 || h.len() == 6) && h.chars().all(|c| c.is_ascii_hexdigit())
+    });
+
+    if hex || NAMED_COLORS.contains(&s) {
+        Ok(s)
+    } else {
+        Err(anyhow!(
+            "color must be #rgb, #rrggbb or one of {}",
+            NAMED_COLORS.join(", ")
+        ))
+    }
+}
+
+/// The icon names `.icon=` takes, and the emoji each stands for.
+pub const NAMED_ICONS: &[(&str, &str)] = &[
+    ("build", "🔨"),
+    ("check", "✅"),
+    ("clock", "⏱️"),
+    ("cross", "❌"),
+    ("database", "🗄️"),
+    ("deploy", "🚀"),
+    ("download", "⬇️"),
+    ("package", "📦"),
+    ("test", "🧪"),
+    ("upload", "⬆️"),
+    ("warning", "⚠️"),
+];
+
+/// Most chars an emoji icon may have: a ZWJ sequence like a family is a few
+/// emoji and the joiners between them.
+const MAX_ICON_CHARS: usize = 8;
+
+/// Icons go before the key's name unescaped by the browser's eye, so only
+/// `NAMED_ICONS` or one short emoji: no ASCII at all, which keeps out markup,
+/// and no spaces or control characters.
+pub fn check_icon(s: &str) -> Result<&str> {
+    let emoji = (1..=MAX_ICON_CHARS).contains(&s.chars().count())
+        && s.chars()
+            .all(|c| !c.is_ascii() && !c.is_whitespace() && !c.is_control());
+
+    if emoji || NAMED_ICONS.iter().any(|(name, _)| *name == s) {
+        Ok(s)
+    } else {
+        Err(anyhow!(
+            "icon must be an emoji or one of {}",
+            Vec::from_iter(NAMED_ICONS.iter().map(|(name, _)| *name)).join(", ")
+        ))
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub const DEFAULT_MAX_STATE_LEN: usize = 512;
+
+/// Longest state accepted, in bytes, see `set_max_state_len`.
+static MAX_STATE_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_STATE_LEN);
+static TRUNCATE_STATE: AtomicBool = AtomicBool::new(false);
+
+/// Sets the longest state a send may have, and whether a longer one is cut
+/// down to that instead of rejected.
+pub fn set_max_state_len(max: usize, truncate: bool) {
+    MAX_STATE_LEN.store(max, Ordering::Relaxed);
+    TRUNCATE_STATE.store(truncate, Ordering::Relaxed);
+}
+
+/// States are free text shown as is, so anything but control characters goes:
+/// every format escapes them its own way, the HTML of /see included.
+pub fn check_state(s: &str) -> Result<&str> {
+    if s.chars().any(char::is_control) {
+        return Err(anyhow!("state must not have control characters"));
+    }
+
+    Ok(s)
+}
+
+/// `state`, or an error if it's longer than `max` bytes. With `truncate` it's
+/// cut at a char boundary instead, the bool says whether it was.
+fn limit_state(state: &str, max: usize, truncate: bool) -> Result<(&str, bool)> {
+    if state.len() <= max {
+        return Ok((state, false));
+    }
+
+    if !truncate {
+        return Err(anyhow!(
+            "state is {} bytes, at most {} are allowed",
+            state.len(),
+            max
+        ));
+    }
+
+    let end = (0..=max)
+        .rev()
+        .find(|&i| state.is_char_boundary(i))
+        .unwrap_or(0);
+
+    Ok((&state[..end], true))
+}
+
+/// Numbers beyond this are taken for typos, see `set_max_magnitude`.
+static MAX_MAGNITUDE: AtomicI64 = AtomicI64::new(1_000_000_000_000_000);
+
+/// Sets the largest absolute value a sent number may have, 0 for no bound
+/// beyond what fits in an i64.
+pub fn set_max_magnitude(max: i64) {
+    MAX_MAGNITUDE.store(max, Ordering::Relaxed);
+}
+
+/// See `set_done_percent`.
+static DONE_PERCENT: AtomicU8 = AtomicU8::new(100);
+
+/// Sets how far along its max a key's current has to be for the key to be
+/// done, from 1 to 100, with 99 for jobs that skip a few of their items. A
+/// state of `done` is done however far along the key is.
+pub fn set_done_percent(percent: u8) -> Result<()> {
+    if !(1..=100).contains(&percent) {
+        return Err(anyhow!("the done percent must be from 1 to 100"));
+    }
+
+    DONE_PERCENT.store(percent, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Whether `current` is far enough along `max` to be done, see
+/// `set_done_percent`.
+fn reaches_done(current: i64, max: i64) -> bool {
+    reaches_percent(current, max, DONE_PERCENT.load(Ordering::Relaxed))
+}
+
+fn reaches_percent(current: i64, max: i64, percent: u8) -> bool {
+    max > 0 && current as i128 * 100 >= percent as i128 * max as i128
+}
+
+pub fn parse_number(s: &str) -> Result<i64> {
+    let n: i64 = s
+        .parse()
+        .map_err(|e: std::num::ParseIntError| match e.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                anyhow!("{} doesn't fit in a 64-bit integer", s)
+            }
+            _ => anyhow!("{:?} isn't a whole number", s),
+        })?;
+
+    let max = MAX_MAGNITUDE.load(Ordering::Relaxed);
+
+    if max > 0 && n.unsigned_abs() > max.unsigned_abs() {
+        return Err(anyhow!(
+            "{} is out of range, numbers must be within ±{}",
+            n,
+            max
+        ));
+    }
+
+    Ok(n)
+}
+
 pub fn parse_i64_or_null(s: &str) -> Result<Option<i64>> {
-    if s.to_ascii_lowercase() == "null" {
+    let s = s.trim();
+
+    if s.eq_ignore_ascii_case("null") {
         Ok(None)
     } else {
-        Ok(Some(s.parse()?))
+        Ok(Some(parse_number(s)?))
     }
 }
 
 const STRING_REQ: &str = "[a-z0-9_.-]";
+const KEY_REQ: &str = "[a-z0-9_.:-]";
 
-#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// The params of `/send` that aren't keys, `batch=` of packed updates and
+/// the ones saying how the send is written.
+pub const SEND_PARAMS: &[&str] = &[
+    "batch", "ts", "format", "verbose", "init", "noop", "idem", "_ttl", "dryrun",
+];
+
+/// Notes may hold a url or a short sentence: anything url-safe, plus spaces.
+pub fn check_note(s: &str) -> Result<&str> {
+    let ok = s.len() <= MAX_NOTE_LEN
+        && s.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == ' ' || "-._~:/?#[]@!$&'()*+,;=%".contains(c)
+        });
+
+    if ok {
+        Ok(s)
+    } else {
+        Err(anyhow!(
+            "note must be at most {} url-safe characters or spaces",
+            MAX_NOTE_LEN
+        ))
+    }
+}
+
+/// `.tag=critical,db`: labels that go across key names, each named like a
+/// key. Repeats are dropped, the rest kept in the order given and stored
+/// joined by commas. An empty list clears them.
+pub fn parse_tags(s: &str) -> Result<Option<String>> {
+    let mut tags: Vec<&str> = vec![];
+
+    for tag in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        check_key(tag).map_err(|_| anyhow!("tag {:?} must be {}", tag, KEY_REQ))?;
+
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(anyhow!("at most {} tags", MAX_TAGS));
+    }
+
+    Ok(if tags.is_empty() {
+        None
+    } else {
+        Some(tags.join(","))
+    })
+}
+
+/// `.segments=done,active,pending`: how many of a key's items are in each
+/// step, shown as one bar in three colors. Each count is a whole number of
+/// at least 0, stored joined by commas; `null` clears them.
+pub fn parse_segments(s: &str) -> Result<Option<String>> {
+    if s.is_empty() || s.eq_ignore_ascii_case("null") {
+        return Ok(None);
+    }
+
+    let counts = s
+        .split(',')
+        .map(|c| parse_number(c.trim()))
+        .collect::<Result<Vec<_>>>()?;
+
+    match counts[..] {
+        [done, active, pending] if done >= 0 && active >= 0 && pending >= 0 => {
+            Ok(Some(format!("{},{},{}", done, active, pending)))
+        }
+        _ => Err(anyhow!(
+            "segments must be three counts of at least 0: done,active,pending"
+        )),
+    }
+}
+
+/// See `parse_segments`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Segments {
+    pub done: i64,
+    pub active: i64,
+    pub pending: i64,
+}
+
+impl Segments {
+    fn from_redis(s: &str) -> Option<Segments> {
+        let mut counts = s.split(',').map(|c| c.parse().ok());
+
+        Some(Segments {
+            done: counts.next()??,
+            active: counts.next()??,
+            pending: counts.next()??,
+        })
+    }
+
+    pub fn total(&self) -> i64 {
+        self.done + self.active + self.pending
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Key {
     token: String,
     pub key: String,
@@ -38,199 +360,6516 @@ pub struct Key {
 impl<S1: Into<String>, S2: Into<String>> TryFrom<(S1, S2)> for Key {
     fn try_from((token, key): (S1, S2)) -> Result<Self> {
         let token = token.into();
-        check_string(&token)?;
+        let key = key.into();
+        check_token(&token)?;
+        check_key(&key)?;
 
-        Ok(Key {
-            token,
-            key: key.into(),
-        })
+        // A send takes these out of its query before parsing keys, so a key
+        // can't be named like one. Stored ones still parse and can be cleared.
+        if SEND_PARAMS.contains(&key.as_str()) {
+            return Err(anyhow!(
+                "key {} is taken by the send param of that name, name the key something else",
+                key
+            ));
+        }
+
+        Ok(Key { token, key })
     }
 
     type Error = anyhow::Error;
 }
 
+/// A key's params live at `pcafe:{token}:key:param`. The braces are a cluster
+/// hash tag: everything of a token, its index included, hashes to one slot, so
+/// multi-key commands and scripts over a token work on Redis Cluster too.
 impl Key {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
     pub fn redis_key(&self, param: &str) -> String {
-        assert!(param.contains(':') == false);
+        assert!(!param.contains(':'));
+
+        format!("pcafe:{{{}}}:{}:{}", self.token, self.key, param)
+    }
+
+    /// Per-token hash of the token's `TokenMeta`.
+    fn meta_key(token: &str) -> String {
+        format!("pcafe-meta:{{{}}}", token)
+    }
+
+    /// A token's key names as a diff poll saw them, by their hash, see
+    /// `Store::save_key_set`.
+    fn key_set_key(token: &str, hash: &str) -> String {
+        format!("pcafe-keyset:{{{}}}:{}", token, hash)
+    }
+
+    /// Per-token flag set by `Store::freeze`, kept without a TTL.
+    fn frozen_key(token: &str) -> String {
+        format!("pcafe-frozen:{{{}}}", token)
+    }
+
+    /// Per-token list of `t:key` completion events, oldest first.
+    fn events_key(token: &str) -> String {
+        format!("pcafe-events:{{{}}}", token)
+    }
+
+    /// Per-token flag of a completion told to Slack, see `Store::mark_notified`.
+    fn notified_key(token: &str) -> String {
+        format!("pcafe-notified:{{{}}}", token)
+    }
+
+    /// Per-token record of a send made with an `Idempotency-Key`, see
+    /// `Store::claim_send`.
+    fn idempotency_key(token: &str, idem: &str) -> String {
+        format!("pcafe-idem:{{{}}}:{}", token, idem)
+    }
+
+    /// Per-token count of /see page views, see `Store::count_view`.
+    fn views_key(token: &str) -> String {
+        format!("pcafe-views:{{{}}}", token)
+    }
 
-        return format!("pcafe:{}:{}:{}", self.token, self.key, param);
+    /// The token a view token reads, see `Store::remember_view`.
+    fn view_key(view: &str) -> String {
+        format!("pcafe-view:{{{}}}", view)
     }
 
-    pub fn redis_key_pattern(&self) -> String {
-        return format!("pcafe:{}:{}*", self.token, self.key);
+    /// Per-token sorted set of key names scored by when they expire. Listing
+    /// reads it instead of SCAN, which on a cluster only sees one node. It's
+    /// outside the `pcafe:` namespace so it can't be taken for a key.
+    fn index_key(token: &str) -> String {
+        format!("pcafe-index:{{{}}}", token)
     }
 
     pub fn from_redis_key(redis_key: &str) -> Result<Key> {
-        let vr: Result<Vec<&str>> = redis_key.split(":").map(check_string).collect();
+        let rest = redis_key
+            .strip_prefix("pcafe:")
+            .ok_or_else(|| anyhow!("Bad structure: {:?}", redis_key))?;
+        let (token, rest) = rest
+            .strip_prefix('{')
+            .and_then(|r| r.split_once("}:"))
+            .ok_or_else(|| anyhow!("token must be in braces"))?;
+        let (key, param) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Bad structure: {:?}", redis_key))?;
 
-        let v = vr?;
+        check_string(param)?;
 
-        match (v[0], v[1], &v[2..v.len() - 1]) {
-            ("pcafe", token, key) => Ok(Key {
-                token: token.to_owned(),
-                key: key.join(":"),
-            }),
-            _ => Err(anyhow!("Bad structure: {:?}", v)),
-        }
+        Ok(Key {
+            token: check_token(token)?.to_owned(),
+            key: check_key(key)?.to_owned(),
+        })
+    }
+
+    /// The key and param of a redis key of the layout before tokens were
+    /// hash-tagged, `pcafe:token:key:param`.
+    fn from_legacy_redis_key(redis_key: &str) -> Result<(Key, &str)> {
+        let rest = redis_key
+            .strip_prefix("pcafe:")
+            .ok_or_else(|| anyhow!("Bad structure: {:?}", redis_key))?;
+        let (token, rest) = rest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Bad structure: {:?}", redis_key))?;
+        let (key, param) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Bad structure: {:?}", redis_key))?;
+
+        let key = Key {
+            token: check_token(token)?.to_owned(),
+            key: check_key(key)?.to_owned(),
+        };
+
+        Ok((key, check_string(param)?))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Update {
     key: Key,
-    state: Option<String>,
+    state: Option<Option<String>>,
     current: Option<Option<i64>>,
     max: Option<Option<i64>>,
+    color: Option<Option<String>>,
+    order: Option<Option<i64>>,
+    error: Option<Option<String>>,
+    note: Option<Option<String>>,
+    persist: Option<Option<i64>>,
+    weight: Option<Option<f64>>,
+    tags: Option<Option<String>>,
+    segments: Option<Option<String>>,
+    duration: Option<Option<i64>>,
+    indeterminate: Option<Option<i64>>,
+    icon: Option<Option<String>>,
+    reverse: Option<Option<i64>>,
+    metric: Option<Option<String>>,
+    /// `+n`: add to whatever current is, applied instead of `current`.
+    increment: Option<i64>,
+    /// `.setmax`: a new max that current is clamped to, see `SET_MAX`.
+    set_max: Option<i64>,
+    /// The sent state was cut down to the max length, see `set_max_state_len`.
+    state_truncated: bool,
+    /// `.resethistory=1`: the key's history starts over, see `Store::get_history`.
+    reset_history: bool,
+    /// Seconds the key lives for instead of the store's TTL, see `with_ttl`.
+    ttl: Option<usize>,
+}
+
+/// What `Store::declare` did, by key count.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Declared {
+    pub added: usize,
+    pub removed: usize,
+    pub kept: usize,
+}
+
+/// See `Update::plan`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UpdatePlan {
+    pub key: String,
+    /// The params written, `null` for the ones deleted.
+    pub set: BTreeMap<&'static str, Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub increment: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub setmax: Option<i64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub state_truncated: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reset_history: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<usize>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
 pub struct Value {
     pub state: Option<String>,
     pub current: Option<i64>,
     pub max: Option<i64>,
+    pub created_at: Option<i64>,
+    pub color: Option<String>,
+    pub order: Option<i64>,
+    pub error: Option<String>,
+    pub note: Option<String>,
+    /// Server time of the last write, whatever it changed.
+    pub updated_at: Option<i64>,
+    /// How much the key counts towards `overall_percent`, 1 when unset.
+    pub weight: Option<f64>,
+    /// See `parse_tags`.
+    pub tags: Vec<String>,
+    pub segments: Option<Segments>,
+    /// Seconds the key is expected to take, for a bar that goes by the clock.
+    pub duration: Option<i64>,
+    /// A spinning bar whatever the numbers say, see `Value::is_done`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub indeterminate: bool,
+    /// See `check_icon`, a name is shown as its emoji, see `Value::icon`.
+    pub icon: Option<String>,
+    /// Current counts down what's left of max, see `Value::is_done`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reverse: bool,
+    #[serde(rename = "type", skip_serializing_if = "Metric::is_progress")]
+    pub metric: Metric,
+    /// Not stored, see `Value::timed`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none", skip_deserializing)]
+    pub timed: Option<Timed>,
+    /// Not stored, see `Value::with_rate`.
+    #[serde(skip_serializing_if = "Option::is_none", skip_deserializing)]
+    pub rate_per_sec: Option<f64>,
 }
 
-impl Update {
-    pub fn new(
-        key: Key,
-        state: Option<String>,
-        current: Option<Option<i64>>,
-        max: Option<Option<i64>>,
-    ) -> Update {
-        Update {
-            key,
-            state,
-            current,
-            max,
-        }
-    }
+/// A key as `/export.ndjson` writes it and `/restore` reads it back: its
+/// value and the milliseconds it had left, `null` for a persisted key. The
+/// history and the other params that aren't part of a `Value` are left out.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExportedKey {
+    pub token: String,
+    pub key: String,
+    pub pttl: Option<i64>,
+    pub value: Value,
+}
 
-    fn as_cmd<T>(&self, param: &str, val: &Option<Option<T>>) -> Option<Cmd>
-    where
-        T: ToOwned,
-        <T as ToOwned>::Owned: ToRedisArgs,
-    {
-        let key = self.key.redis_key(param);
+/// A key of a `duration` and no max, serialized as `"mode": "time"` along
+/// with how long it's been running. It's `overtime` once that's past the
+/// duration without the key being done.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "mode", rename = "time")]
+pub struct Timed {
+    pub elapsed: i64,
+    pub overtime: bool,
+}
 
-        val.as_ref().map(|v| match v {
-            Some(_) => Cmd::set_ex(key, v.as_ref().map(|v| v.to_owned()), EXPIRE_SECONDS),
-            None => Cmd::del(key),
-        })
+/// What a key's numbers are, its `type` param. A counter is a running total
+/// with no max: it's shown as its current, never done by its numbers, and
+/// left out of every percent.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    #[default]
+    Progress,
+    Counter,
+}
+
+impl Metric {
+    pub fn is_progress(&self) -> bool {
+        *self == Metric::Progress
     }
+}
 
-    pub fn as_cmds(&self) -> impl Iterator<Item = Cmd> {
-        [
-            self.as_cmd("state", &Some(self.state.as_ref())),
-            self.as_cmd("current", &self.current),
-            self.as_cmd("max", &self.max),
-        ]
-        .into_iter()
-        .flatten()
+impl std::str::FromStr for Metric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Metric> {
+        match s {
+            "progress" => Ok(Metric::Progress),
+            "counter" => Ok(Metric::Counter),
+            _ => Err(anyhow!("type must be counter or progress")),
+        }
     }
+}
 
-    pub fn from_query(token: &str, (key, val): (String, String)) -> Result<Self> {
-        let (state, rest) = match val.split_once("!") {
-            Some((state, rest)) => (Some(check_string(state)?.to_owned()), rest),
-            None => (None, val.as_ref()),
-        };
-        let (current, max) = match rest.split_once("/") {
-            Some((c, m)) => (c, Some(parse_i64_or_null(m)?)),
-            None => (rest, None),
-        };
+/// The per-key params that make up a `Value`, in `Value::from_redis` order.
+const VALUE_PARAMS: [&str; 17] = [
+    "state",
+    "current",
+    "max",
+    "created_at",
+    "color",
+    "order",
+    "error",
+    "note",
+    "updated_at",
+    "weight",
+    "tags",
+    "segments",
+    "duration",
+    "indeterminate",
+    "icon",
+    "reverse",
+    "type",
+];
 
-        let current = if current == "" {
-            None
-        } else {
-            Some(parse_i64_or_null(current)?)
-        };
+/// Every param stored for a key.
+const KEY_PARAMS: [&str; 21] = [
+    "state",
+    "current",
+    "max",
+    "created_at",
+    "color",
+    "order",
+    "error",
+    "note",
+    "history",
+    "updated_at",
+    "ts",
+    "persist",
+    "weight",
+    "tags",
+    "segments",
+    "duration",
+    "indeterminate",
+    "icon",
+    "reverse",
+    "type",
+    "done_at",
+];
 
-        Ok(Update {
-            key: (token.to_owned(), key).try_into()?,
-            state,
-            current,
-            max,
-        })
-    }
+/// Adds to current, starting from 0, and sets max in the same step, so
+/// there's never a current without a TTL. Returns the new current.
+///
+/// KEYS: current, max, created_at.
+/// ARGV: delta, ttl, now, then max, `-` to delete it or empty to leave it.
+const INCREMENT: &str = r"
+local current = redis.call('INCRBY', KEYS[1], ARGV[1])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+
+if ARGV[4] == '-' then
+    redis.call('DEL', KEYS[2])
+elseif ARGV[4] ~= '' then
+    redis.call('SET', KEYS[2], ARGV[4], 'EX', ARGV[2])
+end
+
+redis.call('SET', KEYS[3], ARGV[3], 'NX', 'EX', ARGV[2])
+redis.call('EXPIRE', KEYS[3], ARGV[2])
+
+return current
+";
+
+/// Lua for `history_cmds`, for the scripts that write a history point
+/// themselves. Defines `push_history(history, t, current, len, ttl)`.
+macro_rules! push_history_lua {
+    () => {
+        r"
+local function push_history(history, t, current, len, ttl)
+    redis.call('RPUSH', history, t .. ':' .. current)
+    redis.call('LTRIM', history, -tonumber(len), -1)
+    redis.call('EXPIRE', history, ttl)
+end
+"
+    };
 }
 
-#[derive(Clone)]
-pub struct Store<C: redis::aio::ConnectionLike + AsyncCommands + Clone> {
-    redis: C,
+/// Appends a history point of whatever current an increment left, inside the
+/// transaction that did the increment, keeping the last `len` points.
+///
+/// KEYS: current, history.
+/// ARGV: t, len, ttl.
+const PUSH_CURRENT: &str = concat!(
+    push_history_lua!(),
+    r"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return
+end
+
+push_history(KEYS[2], ARGV[1], current, ARGV[2], ARGV[3])
+"
+);
+
+/// Sets max and lowers current to it in one step, so a revised total never
+/// leaves current past max. Returns current as it was.
+///
+/// KEYS: max, current.
+/// ARGV: max, ttl.
+const SET_MAX: &str = r"
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+
+local current = tonumber(redis.call('GET', KEYS[2]))
+if current and current > tonumber(ARGV[1]) then
+    redis.call('SET', KEYS[2], ARGV[1], 'EX', ARGV[2])
+end
+
+return current
+";
+
+/// Applies an update only if its timestamp isn't older than the stored
+/// `ts`, the newest timestamp applied so far, returns 1 if it was applied.
+/// What only an applied update writes, its index entry and its history point,
+/// is written here too.
+///
+/// KEYS: ts, created_at, updated_at, the index, history, then the touched
+/// params.
+/// ARGV: timestamp, ttl, now, the key's name, how many points to keep, the
+/// current for a history point or empty for none, then per param `=value`
+/// to set or `-` to delete.
+const SET_IF_NEWER: &str = concat!(
+    push_history_lua!(),
+    r"
+local stored = tonumber(redis.call('GET', KEYS[1]))
+if stored and tonumber(ARGV[1]) < stored then
+    return 0
+end
+
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+redis.call('SET', KEYS[2], ARGV[3], 'NX', 'EX', ARGV[2])
+redis.call('EXPIRE', KEYS[2], ARGV[2])
+redis.call('SET', KEYS[3], ARGV[3], 'EX', ARGV[2])
+
+for i = 6, #KEYS do
+    local v = ARGV[i + 1]
+    if v == '-' then
+        redis.call('DEL', KEYS[i])
+    else
+        redis.call('SET', KEYS[i], string.sub(v, 2), 'EX', ARGV[2])
+    end
+end
+
+redis.call('ZADD', KEYS[4], tonumber(ARGV[3]) + tonumber(ARGV[2]), ARGV[4])
+if ARGV[6] ~= '' then
+    push_history(KEYS[5], ARGV[3], ARGV[6], ARGV[5], ARGV[2])
+end
+
+return 1
+"
+);
+
+/// Writes an update only if the key has none of its `VALUE_PARAMS` yet, so
+/// a restarted job can't reset a bar that's already moved. Returns 1 if it
+/// was written. Like `SET_IF_NEWER`, the index entry and the history point
+/// are written with it.
+///
+/// KEYS: created_at, updated_at, the other value params, the index, history,
+/// then the touched params.
+/// ARGV: how many value params, ttl, now, the key's name, how many points to
+/// keep, the current for a history point or empty for none, then per touched
+/// param `=value` to set or `-` to delete.
+const INIT_IF_ABSENT: &str = concat!(
+    push_history_lua!(),
+    r"
+local params = tonumber(ARGV[1])
+
+for i = 1, params do
+    if redis.call('EXISTS', KEYS[i]) == 1 then
+        return 0
+    end
+end
+
+redis.call('SET', KEYS[1], ARGV[3], 'EX', ARGV[2])
+redis.call('SET', KEYS[2], ARGV[3], 'EX', ARGV[2])
+
+for i = params + 3, #KEYS do
+    local v = ARGV[i - params + 4]
+    if v ~= '-' then
+        redis.call('SET', KEYS[i], string.sub(v, 2), 'EX', ARGV[2])
+    end
+end
+
+redis.call('ZADD', KEYS[params + 1], tonumber(ARGV[3]) + tonumber(ARGV[2]), ARGV[4])
+if ARGV[6] ~= '' then
+    push_history(KEYS[params + 2], ARGV[3], ARGV[6], ARGV[5], ARGV[2])
+end
+
+return 1
+"
+);
+
+/// Lua for `Value::is_done`, put in front of the scripts that need to tell so
+/// that they can't drift apart from each other or from it. Defines
+/// `is_done(keys, percent)`, `keys` naming the key's state, current, max,
+/// error, indeterminate, reverse and type.
+macro_rules! is_done_lua {
+    () => {
+        r"
+local function is_done(keys, percent)
+    if redis.call('EXISTS', keys.error) == 1 then
+        return false
+    end
+
+    local state = redis.call('GET', keys.state)
+    if state and string.lower(state) == 'done' then
+        return true
+    end
+
+    if redis.call('EXISTS', keys.indeterminate) == 1
+        or redis.call('GET', keys.type) == 'counter' then
+        return false
+    end
+
+    local current = tonumber(redis.call('GET', keys.current))
+    local max = tonumber(redis.call('GET', keys.max))
+    if not current then
+        return false
+    end
+
+    if redis.call('EXISTS', keys.reverse) == 1 then
+        return current <= 0
+            or (max ~= nil and max > 0 and (max - current) * 100 >= percent * max)
+    end
+
+    return max ~= nil and max > 0 and current * 100 >= percent * max
+end
+"
+    };
 }
 
-impl<C: redis::aio::ConnectionLike + AsyncCommands + Clone> Store<C> {
-    pub fn new(redis: C) -> Store<C> {
-        Store { redis }
-    }
+/// Removes the TTL of a finished key that's meant to be kept, see
+/// `Store::with_persist_done`, and of any key of a frozen token, see
+/// `Store::freeze`. Done is `is_done_lua`. Its index entry is scored `+inf`
+/// and the index kept too, otherwise the key would drop off `/see` once the
+/// index expired. Returns 1 if persisted.
+///
+/// KEYS: state, current, max, error, persist, the index, the frozen flag, the
+/// meta, the events, indeterminate, reverse, type, then every param.
+/// ARGV: the key's name, `1` to persist any finished key, the done percent.
+const PERSIST_IF_DONE: &str = concat!(
+    is_done_lua!(),
+    r"
+local frozen = redis.call('EXISTS', KEYS[7]) == 1
 
-    pub async fn update(&self, update: &Update) -> Result<()> {
-        for c in update.as_cmds() {
-            c.query_async(&mut self.redis.clone()).await?;
-        }
+if not frozen then
+    if ARGV[2] ~= '1' and redis.call('GET', KEYS[5]) ~= '1' then
+        return 0
+    end
 
-        Ok(())
+    local keys = {
+        state = KEYS[1], current = KEYS[2], max = KEYS[3], error = KEYS[4],
+        indeterminate = KEYS[10], reverse = KEYS[11], type = KEYS[12],
     }
+    if not is_done(keys, tonumber(ARGV[3])) then
+        return 0
+    end
+end
 
-    async fn get_param<T: FromRedisValue>(&self, key: &Key, param: &str) -> Result<Option<T>> {
-        Cmd::get(key.redis_key(param))
-            .query_async(&mut self.redis.clone())
-            .await
-            .map_err(|e| anyhow::Error::new(e))
-    }
+for i = 13, #KEYS do
+    redis.call('PERSIST', KEYS[i])
+end
+redis.call('ZADD', KEYS[6], '+inf', ARGV[1])
+redis.call('PERSIST', KEYS[6])
 
-    pub async fn get_state(&self, key: &Key) -> Result<Value> {
-        Ok(Value {
-            state: self.get_param(key, "state").await?,
-            current: self.get_param(key, "current").await?,
-            max: self.get_param(key, "max").await?,
-        })
-    }
+if frozen then
+    redis.call('PERSIST', KEYS[8])
+    redis.call('PERSIST', KEYS[9])
+end
 
-    pub async fn get_all_keys(&self, token: &str, keyprefix: &str) -> Result<HashSet<Key>> {
-        let kpref = Key::try_from((token.to_owned(), keyprefix.to_owned()))?;
+return 1
+"
+);
 
-        Ok(self
-            .redis
-            .clone()
-            .scan_match(kpref.redis_key_pattern())
-            .await?
-            .filter_map(|v: String| async move { Key::from_redis_key(&v).ok() })
-            .collect::<HashSet<Key>>()
-            .await)
-    }
+/// Logs a key's completion to the token's events as it becomes done, done
+/// being `is_done_lua`. `done_at` marks a done key so that later writes to it
+/// don't log it again; it's cleared once the key is no longer done, so a key
+/// that's rerun logs its next completion too.
+///
+/// KEYS: state, current, max, error, done_at, the events, indeterminate,
+/// reverse, type.
+/// ARGV: the key's name, now, ttl, how many events to keep, the done percent.
+const RECORD_DONE: &str = concat!(
+    is_done_lua!(),
+    r"
+local keys = {
+    state = KEYS[1], current = KEYS[2], max = KEYS[3], error = KEYS[4],
+    indeterminate = KEYS[7], reverse = KEYS[8], type = KEYS[9],
 }
 
-#[cfg(test)]
-mod tests {
-    use anyhow::Result;
-    use redis::aio::ConnectionManager;
+if not is_done(keys, tonumber(ARGV[5])) then
+    redis.call('DEL', KEYS[5])
+    return 0
+end
 
-    use crate::store::{Store, Update};
+if not redis.call('SET', KEYS[5], ARGV[2], 'NX', 'EX', ARGV[3]) then
+    redis.call('EXPIRE', KEYS[5], ARGV[3])
+    return 0
+end
 
-    #[tokio::test]
-    async fn it_works() -> Result<()> {
-        let client = redis::Client::open("redis://127.0.0.1/")?;
-        let conm = ConnectionManager::new(client).await?;
+redis.call('RPUSH', KEYS[6], ARGV[2] .. ':' .. ARGV[1])
+redis.call('LTRIM', KEYS[6], -tonumber(ARGV[4]), -1)
+redis.call('EXPIRE', KEYS[6], ARGV[3])
 
-        let store = Store::new(conm);
+return 1
+"
+);
 
-        store
-            .update(&Update::new(
-                ("randomtoken", "some:key").try_into()?,
-                None,
-                Some(Some(0)),
-                Some(Some(10)),
-            ))
-            .await?;
+/// Expires the index with the keys in it, unless it holds a persisted key or
+/// has longer left already, for a key sent with a longer `_ttl`.
+///
+/// KEYS: the index.
+/// ARGV: ttl.
+const EXPIRE_INDEX: &str = r"
+if redis.call('ZCOUNT', KEYS[1], '+inf', '+inf') == 0
+    and redis.call('TTL', KEYS[1]) < tonumber(ARGV[1]) then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+";
 
-        let keys = store.get_all_keys("randomtoken", "some:").await?;
+/// Expires each of the keys in `ttl` unless it has longer left already.
+///
+/// KEYS: the keys.
+/// ARGV: ttl.
+const EXTEND_EXPIRE: &str = r"
+for _, key in ipairs(KEYS) do
+    if redis.call('TTL', key) < tonumber(ARGV[1]) then
+        redis.call('EXPIRE', key, ARGV[1])
+    end
+end
+";
 
-        println!("get_all_keys {:?}", &keys);
+/// Points a view at its token unless it already does with more than half
+/// of `ttl` left, so the sends keeping it alive needn't write it each time.
+/// Returns 1 if it was written.
+///
+/// KEYS: the view.
+/// ARGV: token, ttl.
+const REMEMBER_VIEW: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] and redis.call('TTL', KEYS[1]) * 2 > tonumber(ARGV[2]) then
+    return 0
+end
 
-        for key in keys {
-            let st = store.get_state(&key).await?;
-            println!("{:?} .. {:?}", key, st);
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+return 1
+";
+
+/// Moves a redis key of the old layout to its new name unless that's been
+/// written since, and drops it either way. Gone already, it's been moved.
+///
+/// KEYS: the old name, the new one.
+const MOVE_LEGACY: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return
+end
+
+if redis.call('RENAMENX', KEYS[1], KEYS[2]) == 0 then
+    redis.call('DEL', KEYS[1])
+end
+";
+
+impl Value {
+    /// Nothing is stored for the key, it expired or was never written.
+    pub fn is_empty(&self) -> bool {
+        *self == Value::default()
+    }
+
+    /// `v` has the key's `VALUE_PARAMS` in order. A param that doesn't parse,
+    /// like a `current` written by something else, is logged and read as
+    /// missing, rather than failing the key and the page it's on.
+    fn from_redis(key: &Key, v: &[redis::Value]) -> Value {
+        fn parse<T: std::str::FromStr>(key: &Key, v: &[redis::Value], i: usize) -> Option<T> {
+            let s = match redis::from_redis_value::<Option<String>>(&v[i]) {
+                Ok(s) => s?,
+                Err(e) => {
+                    eprintln!(
+                        "Ignoring {} of {}/{}: {}",
+                        VALUE_PARAMS[i], key.token, key.key, e
+                    );
+                    return None;
+                }
+            };
+
+            match s.parse() {
+                Ok(t) => Some(t),
+                Err(_) => {
+                    eprintln!(
+                        "Ignoring {} of {}/{}: can't parse {:?}",
+                        VALUE_PARAMS[i], key.token, key.key, s
+                    );
+                    None
+                }
+            }
+        }
+        let param = |i| parse::<String>(key, v, i);
+        let number = |i| parse::<i64>(key, v, i);
+
+        Value {
+            state: param(0),
+            current: number(1),
+            max: number(2),
+            created_at: number(3),
+            color: param(4),
+            order: number(5),
+            error: param(6),
+            note: param(7),
+            updated_at: number(8),
+            weight: parse(key, v, 9),
+            tags: param(10)
+                .map(|t| t.split(',').map(str::to_owned).collect())
+                .unwrap_or_default(),
+            segments: param(11).as_deref().and_then(Segments::from_redis),
+            duration: number(12),
+            indeterminate: param(13).is_some(),
+            icon: param(14),
+            reverse: param(15).is_some(),
+            metric: parse(key, v, 16).unwrap_or_default(),
+            timed: None,
+            rate_per_sec: None,
         }
+    }
+
+    /// What `from_redis` reads each of `VALUE_PARAMS` from, `None` where
+    /// nothing is stored.
+    fn to_redis(&self) -> [Option<String>; VALUE_PARAMS.len()] {
+        let string = |v: &Option<i64>| v.map(|v| v.to_string());
+
+        [
+            self.state.clone(),
+            string(&self.current),
+            string(&self.max),
+            string(&self.created_at),
+            self.color.clone(),
+            string(&self.order),
+            self.error.clone(),
+            self.note.clone(),
+            string(&self.updated_at),
+            self.weight.map(|w| w.to_string()),
+            Some(self.tags.join(",")).filter(|t| !t.is_empty()),
+            self.segments
+                .map(|s| format!("{},{},{}", s.done, s.active, s.pending)),
+            string(&self.duration),
+            self.indeterminate.then(|| "1".to_owned()),
+            self.icon.clone(),
+            self.reverse.then(|| "1".to_owned()),
+            (!self.metric.is_progress()).then(|| "counter".to_owned()),
+        ]
+    }
+
+    /// The emoji to show for `icon`, which may be a name of `NAMED_ICONS`.
+    pub fn icon(&self) -> Option<&str> {
+        let icon = self.icon.as_deref()?;
+
+        Some(
+            NAMED_ICONS
+                .iter()
+                .find(|(name, _)| *name == icon)
+                .map_or(icon, |(_, emoji)| emoji),
+        )
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight.unwrap_or(1.0)
+    }
+
+    /// Done once current reaches a known max, or 0 for a `reverse` key, or
+    /// when the state says `done`. Reaching means getting to the done percent
+    /// of the max, see `set_done_percent`. A failed key is never done, however far
+    /// it got, and an indeterminate one or a counter only by its state.
+    pub fn is_done(&self) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+
+        let reached = match (self.current, self.max) {
+            _ if self.indeterminate || !self.metric.is_progress() => false,
+            (Some(c), m) if self.reverse => {
+                c <= 0 || m.is_some_and(|m| reaches_done(m.saturating_sub(c), m))
+            }
+            (Some(c), Some(m)) => reaches_done(c, m),
+            _ => false,
+        };
+
+        reached
+            || self
+                .state
+                .as_deref()
+                .is_some_and(|s| s.eq_ignore_ascii_case("done"))
+    }
+
+    /// Failed when it has an error, or when a word of its state is one of
+    /// `FAILURE_KEYWORDS`. Done takes precedence, see `summarize`.
+    pub fn is_failed(&self) -> bool {
+        self.error.is_some()
+            || self.state.as_deref().is_some_and(|s| {
+                s.split(|c: char| !c.is_alphanumeric())
+                    .any(|w| FAILURE_KEYWORDS.iter().any(|k| w.eq_ignore_ascii_case(k)))
+            })
+    }
+
+    /// Declared but not started: a known max and no current yet. That's 0 of
+    /// the max rather than an indeterminate bar, and isn't running either.
+    pub fn is_queued(&self) -> bool {
+        self.current.is_none()
+            && !self.indeterminate
+            && self.metric.is_progress()
+            && self.max.is_some_and(|m| m > 0)
+            && self.error.is_none()
+            && !self.is_done()
+    }
+
+    /// How far along the key is, from 0 to 100: its current over its max,
+    /// clamped, or 100 once it's done. `None` while that isn't known, which
+    /// includes a max of 0, an indeterminate key and a counter, and a missing
+    /// max however `PCAFE_MISSING_MAX` draws it. A queued key is at 0, see
+    /// `is_queued`.
+    pub fn percent(&self) -> Option<f64> {
+        if self.is_done() {
+            return Some(100.0);
+        }
+
+        if self.indeterminate || !self.metric.is_progress() {
+            return None;
+        }
+
+        if let (Some(Timed { elapsed, .. }), Some(duration)) = (self.timed, self.duration) {
+            return Some((elapsed as f64 * 100.0 / duration as f64).min(100.0));
+        }
+
+        percent_of(known_totals(std::iter::once(self)))
+    }
+
+    /// Seconds since the key was first written, if that's known.
+    pub fn running_for(&self, now: i64) -> Option<i64> {
+        self.created_at.map(|c| (now - c).max(0))
+    }
+
+    /// A key with a duration but no max goes by the clock instead of by its
+    /// current: its percent is the time it's been running over the duration,
+    /// as of `now`, see `Timed`.
+    pub fn timed(self, now: i64) -> Value {
+        let timed = match (self.duration, self.max, self.running_for(now)) {
+            (Some(duration), None, Some(elapsed)) => Some(Timed {
+                elapsed,
+                overtime: elapsed > duration && !self.is_done(),
+            }),
+            _ => None,
+        };
+
+        Value { timed, ..self }
+    }
+
+    /// The key with its rate from `history`, see `rate_per_sec`.
+    pub fn with_rate(self, history: &[HistoryPoint]) -> Value {
+        Value {
+            rate_per_sec: rate_per_sec(history),
+            ..self
+        }
+    }
+
+    /// Seconds since the key was last written, if that's known.
+    pub fn idle_for(&self, now: i64) -> Option<i64> {
+        self.updated_at.map(|u| (now - u).max(0))
+    }
+
+    /// What's left to max over `rate_per_sec`, rounded up. Only for a key
+    /// that isn't done yet and has both a max and a rate, see `with_rate`;
+    /// a reversed key gets closer by counting down.
+    pub fn eta(&self) -> Option<Eta> {
+        if self.is_done() || self.indeterminate || !self.metric.is_progress() {
+            return None;
+        }
+
+        let (current, max, rate) = (
+            self.current?,
+            self.max.filter(|m| *m > 0)?,
+            self.rate_per_sec?,
+        );
+        let (left, rate) = match self.reverse {
+            true => (current, -rate),
+            false => (max - current, rate),
+        };
+
+        if rate <= 0.0 {
+            return Some(Eta::Stalled);
+        }
+
+        Some(Eta::Seconds((left as f64 / rate).ceil() as i64))
+    }
+}
+
+/// How long a key has left at its recent rate, see `Value::eta`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eta {
+    Seconds(i64),
+    /// It isn't getting any closer to its max.
+    Stalled,
+}
+
+/// A token's title and description, shown above its keys on /see.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct TokenMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Cut from the start of the keys' names on the /see page, see
+    /// `TokenMeta::label`. The keys themselves keep it.
+    pub strip_prefix: Option<String>,
+}
+
+impl TokenMeta {
+    /// Changes from a `/meta` query: `title`, `description` and
+    /// `strip_prefix` are free text, an empty value clears the field and
+    /// other params are errors. Returns the fields to set, `None` meaning a
+    /// delete.
+    pub fn changes_from_query(
+        query: &[(String, String)],
+    ) -> Result<Vec<(&'static str, Option<String>)>> {
+        query
+            .iter()
+            .map(|(k, v)| {
+                let (field, max_len) = match k.as_str() {
+                    "title" => ("title", MAX_TITLE_LEN),
+                    "description" => ("description", MAX_DESCRIPTION_LEN),
+                    "strip_prefix" => ("strip_prefix", MAX_TITLE_LEN),
+                    _ => return Err(anyhow!("unknown meta field {:?}", k)),
+                };
+                let v = v.trim();
+
+                if v.len() > max_len {
+                    return Err(anyhow!("{} must be at most {} bytes", field, max_len));
+                }
+
+                Ok((field, Some(v.to_owned()).filter(|v| !v.is_empty())))
+            })
+            .collect()
+    }
+
+    /// The name `key` is shown by: without `strip_prefix`, unless it doesn't
+    /// start with it or is nothing but it.
+    pub fn label<'a>(&self, key: &'a str) -> &'a str {
+        self.strip_prefix
+            .as_deref()
+            .and_then(|p| key.strip_prefix(p))
+            .filter(|rest| !rest.is_empty())
+            .unwrap_or(key)
+    }
+}
+
+impl Update {
+    pub fn new(
+        key: Key,
+        state: Option<String>,
+        current: Option<Option<i64>>,
+        max: Option<Option<i64>>,
+    ) -> Update {
+        Update {
+            state: Some(state),
+            current,
+            max,
+            ..Update::untouched(key)
+        }
+    }
+
+    /// An update touching nothing yet, see `UpdateBuilder`.
+    pub fn builder(key: Key) -> UpdateBuilder {
+        UpdateBuilder(Update::untouched(key))
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    pub fn state_truncated(&self) -> bool {
+        self.state_truncated
+    }
+
+    /// What writing this update would do, for a dry run.
+    pub fn plan(&self) -> UpdatePlan {
+        UpdatePlan {
+            key: self.key.key.clone(),
+            set: self.changes().into_iter().collect(),
+            increment: self.increment,
+            setmax: self.set_max,
+            state_truncated: self.state_truncated,
+            reset_history: self.reset_history,
+            ttl: self.ttl,
+        }
+    }
+
+    /// The update with the key, its history included, expiring `ttl` seconds
+    /// after it instead of the store's TTL, a send's `_ttl=`. `None` keeps the
+    /// store's.
+    pub fn with_ttl(self, ttl: Option<usize>) -> Update {
+        Update { ttl, ..self }
+    }
+
+    fn untouched(key: Key) -> Update {
+        Update {
+            key,
+            state: None,
+            current: None,
+            max: None,
+            color: None,
+            order: None,
+            error: None,
+            note: None,
+            persist: None,
+            weight: None,
+            tags: None,
+            segments: None,
+            duration: None,
+            indeterminate: None,
+            icon: None,
+            reverse: None,
+            metric: None,
+            increment: None,
+            set_max: None,
+            state_truncated: false,
+            reset_history: false,
+            ttl: None,
+        }
+    }
+
+    fn as_cmd<T>(&self, param: &str, val: &Option<Option<T>>, ttl: usize) -> Option<Cmd>
+    where
+        T: ToOwned,
+        <T as ToOwned>::Owned: ToRedisArgs,
+    {
+        let key = self.key.redis_key(param);
+
+        val.as_ref().map(|v| match v {
+            Some(_) => Cmd::set_ex(key, v.as_ref().map(|v| v.to_owned()), ttl),
+            None => Cmd::del(key),
+        })
+    }
+
+    /// `created_at` is only written when missing, but its TTL is refreshed
+    /// along with the other params so it doesn't expire before them.
+    fn created_at_cmds(&self, ttl: usize) -> [Cmd; 2] {
+        let key = self.key.redis_key("created_at");
+
+        let mut set_nx = redis::cmd("SET");
+        set_nx
+            .arg(&key)
+            .arg(unix_now())
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl);
+
+        [set_nx, Cmd::expire(key, ttl)]
+    }
+
+    /// Every param this update touches, `None` meaning a delete.
+    fn changes(&self) -> Vec<(&'static str, Option<String>)> {
+        fn change<T: ToString>(
+            param: &'static str,
+            val: &Option<Option<T>>,
+        ) -> Option<(&'static str, Option<String>)> {
+            val.as_ref()
+                .map(|v| (param, v.as_ref().map(ToString::to_string)))
+        }
+
+        [
+            change("state", &self.state),
+            change("current", &self.current),
+            change("max", &self.max),
+            change("color", &self.color),
+            change("order", &self.order),
+            change("error", &self.error),
+            change("note", &self.note),
+            change("persist", &self.persist),
+            change("weight", &self.weight),
+            change("tags", &self.tags),
+            change("segments", &self.segments),
+            change("duration", &self.duration),
+            change("indeterminate", &self.indeterminate),
+            change("icon", &self.icon),
+            change("reverse", &self.reverse),
+            change("type", &self.metric),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// EXPIREs for the params this update leaves as they are, so clearing or
+    /// setting one field doesn't leave the others to expire sooner.
+    fn refresh_cmds(&self, ttl: usize) -> Vec<Cmd> {
+        // An increment writes current and max itself, see `INCREMENT`
+        let incremented = self.increment.is_some();
+
+        [
+            ("state", self.state.is_some()),
+            ("current", self.current.is_some() || incremented),
+            (
+                "max",
+                self.max.is_some() || incremented || self.set_max.is_some(),
+            ),
+            ("color", self.color.is_some()),
+            ("order", self.order.is_some()),
+            ("error", self.error.is_some()),
+            ("note", self.note.is_some()),
+            ("persist", self.persist.is_some()),
+            ("weight", self.weight.is_some()),
+            ("tags", self.tags.is_some()),
+            ("segments", self.segments.is_some()),
+            ("duration", self.duration.is_some()),
+            ("indeterminate", self.indeterminate.is_some()),
+            ("icon", self.icon.is_some()),
+            ("reverse", self.reverse.is_some()),
+            ("type", self.metric.is_some()),
+            // Written by `SET_IF_NEWER` alone, but it has to outlive plain
+            // sends for a late ts send to still be refused
+            ("ts", false),
+        ]
+        .into_iter()
+        .filter(|(_, touched)| !touched)
+        .map(|(param, _)| Cmd::expire(self.key.redis_key(param), ttl))
+        .collect()
+    }
+
+    /// Commands writing this update with a TTL of `ttl` seconds.
+    pub fn as_cmds(&self, ttl: usize) -> impl Iterator<Item = Cmd> {
+        let [set_created_at, expire_created_at] = self.created_at_cmds(ttl);
+
+        // An increment sets max along with current, see `INCREMENT`
+        let max = match self.increment {
+            Some(_) => None,
+            None => self.as_cmd("max", &self.max, ttl),
+        };
+
+        [
+            self.as_cmd("state", &self.state, ttl),
+            self.as_cmd("current", &self.current, ttl),
+            max,
+            self.as_cmd("color", &self.color, ttl),
+            self.as_cmd("order", &self.order, ttl),
+            self.as_cmd("error", &self.error, ttl),
+            self.as_cmd("note", &self.note, ttl),
+            self.as_cmd("persist", &self.persist, ttl),
+            self.as_cmd("weight", &self.weight, ttl),
+            self.as_cmd("tags", &self.tags, ttl),
+            self.as_cmd("segments", &self.segments, ttl),
+            self.as_cmd("duration", &self.duration, ttl),
+            self.as_cmd("indeterminate", &self.indeterminate, ttl),
+            self.as_cmd("icon", &self.icon, ttl),
+            self.as_cmd("reverse", &self.reverse, ttl),
+            self.as_cmd("type", &self.metric, ttl),
+            self.reset_history
+                .then(|| Cmd::del(self.key.redis_key("history"))),
+            Some(set_created_at),
+            Some(expire_created_at),
+            Some(Cmd::set_ex(
+                self.key.redis_key("updated_at"),
+                unix_now(),
+                ttl,
+            )),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.refresh_cmds(ttl))
+    }
+
+    /// Parses a `key=[state!][current][/max]` query pair, where current can
+    /// also be `+n` to add to the stored value (`%2B` in a url):
+    ///
+    /// * `5/100` sets both numbers, `5` (or `5/`) only current, `/100` only max
+    /// * `null` in either position clears that number, e.g. `/null` clears max
+    /// * an empty `current` or `max` leaves that number untouched, so a
+    ///   state-only value like `compiling!` changes nothing but the state
+    /// * whitespace around the value and around each number is ignored
+    ///
+    /// The state is free text, see `check_state`, and ends at the last `!`
+    /// since the numbers never have one: `Done! 3 of 10!3/10` is state
+    /// `Done! 3 of 10`, and a state that ends in `!` takes another, `Done!!`.
+    ///
+    /// `key.<attribute>=value` sets just that attribute instead, see `from_attribute`.
+    ///
+    /// Form encoders send repeated fields as `key[]=` or `key[0]=`, that's
+    /// the same as `key=`, see `strip_array_suffix`.
+    pub fn from_query(token: &str, (key, val): (String, String)) -> Result<Self> {
+        let key = strip_array_suffix(&key).to_owned();
+        // A `+` in a query string decodes to a space, so `k=+1/200` arrives
+        // as ` 1/200`. A current of one space and then digits was sent as an
+        // increment, before trimming takes the space away.
+        let current = val.rsplit_once('!').map_or(val.as_str(), |(_, rest)| rest);
+        let decoded_plus = current
+            .strip_prefix(' ')
+            .is_some_and(|c| c.starts_with(|c: char| c.is_ascii_digit()));
+        let val = val.trim();
+
+        if let Some((name, attribute)) = key.rsplit_once('.') {
+            let name: Key = (token.to_owned(), name).try_into()?;
+
+            if let Some(update) = Update::from_attribute(name, attribute, val)? {
+                return Ok(update);
+            }
+        }
+
+        let (state, state_truncated, rest) = match val.rsplit_once('!') {
+            Some((state, rest)) => {
+                let (state, truncated) = limit_state(
+                    check_state(state)?,
+                    MAX_STATE_LEN.load(Ordering::Relaxed),
+                    TRUNCATE_STATE.load(Ordering::Relaxed),
+                )?;
+
+                (Some(state.to_owned()), truncated, rest)
+            }
+            None => (None, false, val),
+        };
+        let (current, max) = match rest.split_once("/") {
+            Some((c, m)) if m.trim().is_empty() => (c, None),
+            Some((c, m)) => (c, Some(parse_i64_or_null(m)?)),
+            None => (rest, None),
+        };
+
+        let (current, increment) = match current.trim() {
+            "" => (None, None),
+            c => match c.strip_prefix('+') {
+                Some(delta) => (None, Some(parse_number(delta)?)),
+                None if decoded_plus => (None, Some(parse_number(c)?)),
+                None => (Some(parse_i64_or_null(c)?), None),
+            },
+        };
+
+        Ok(Update {
+            increment,
+            state_truncated,
+            ..Update::new((token.to_owned(), key).try_into()?, state, current, max)
+        })
+    }
+
+    /// Known attributes, `null` resets any of them:
+    ///
+    /// * `state`, `current`, `max`: set or, with `null`, delete just that
+    ///   field, e.g. `build.max=null` keeps the state and current
+    /// * `setmax`: a new positive max, with current lowered to it if it was
+    ///   past it, atomically, see `SET_MAX`. Not `null`-able
+    /// * `color`: the bar color, `#rgb`/`#rrggbb` or a plain name
+    /// * `order`: an integer, keys are listed by it before their names
+    /// * `error`: free text marking the key as failed, an empty value clears it too.
+    ///   It sticks through any other update, numbers and state included, until
+    ///   it's cleared
+    /// * `note`: a url or short text shown under the bar, see `check_note`
+    /// * `persist`: `1` keeps the key without a TTL once it's done, `0` stops
+    ///   that, see `Store::with_persist_done`
+    /// * `weight`: a positive number, how much the key counts towards the
+    ///   overall and group percents; `null` is the default of 1
+    /// * `tag`: comma-separated labels to filter and summarize by, see
+    ///   `parse_tags`; `null` clears them
+    /// * `segments`: done, active and pending counts for one bar in three
+    ///   colors, see `parse_segments`
+    /// * `duration`: seconds the key is expected to take, its bar then goes
+    ///   by the time since it was created while it has no max, see `Value::timed`
+    /// * `indeterminate`: `1` for a spinning bar whatever current and max
+    ///   are, which then don't make it done or count towards any percent;
+    ///   `0` goes back to the numbers
+    /// * `icon`: an emoji or a name of `NAMED_ICONS`, shown before the key's
+    ///   name, see `check_icon`
+    /// * `reverse`: `1` for a key whose current counts down to 0, like items
+    ///   left: its bar fills as current drops and it's done at 0
+    /// * `type`: `counter` for a running total with no bar, see `Metric`;
+    ///   `progress` goes back to a bar
+    /// * `resethistory`: `1` empties the key's history, so the sparkline and
+    ///   the rate start over for a new run; the rest of the key is kept
+    ///
+    /// Other suffixes aren't attributes, so `v1.2=5` is still key `v1.2`.
+    fn from_attribute(key: Key, attribute: &str, val: &str) -> Result<Option<Self>> {
+        let null = val.eq_ignore_ascii_case("null");
+        let builder = Update::builder(key.clone());
+        let update = Update::untouched(key);
+
+        Ok(Some(match attribute {
+            "state" if null || val.is_empty() => builder.clear_state().build(),
+            "state" => {
+                let (state, state_truncated) = limit_state(
+                    check_state(val)?,
+                    MAX_STATE_LEN.load(Ordering::Relaxed),
+                    TRUNCATE_STATE.load(Ordering::Relaxed),
+                )?;
+
+                Update {
+                    state_truncated,
+                    ..builder.state(state).build()
+                }
+            }
+            "current" => match parse_i64_or_null(val)? {
+                Some(current) => builder.current(current).build(),
+                None => builder.clear_current().build(),
+            },
+            "resethistory" if val == "1" => Update {
+                reset_history: true,
+                ..update
+            },
+            "resethistory" => return Err(anyhow!("resethistory must be 1")),
+            "setmax" => match parse_number(val)? {
+                max if max > 0 => Update {
+                    set_max: Some(max),
+                    ..update
+                },
+                _ => return Err(anyhow!("setmax must be positive")),
+            },
+            "max" => match parse_i64_or_null(val)? {
+                Some(max) => builder.max(max).build(),
+                None => builder.clear_max().build(),
+            },
+            "icon" => Update {
+                icon: Some(if null || val.is_empty() {
+                    None
+                } else {
+                    Some(check_icon(val)?.to_owned())
+                }),
+                ..update
+            },
+            "color" => Update {
+                color: Some(if null {
+                    None
+                } else {
+                    Some(check_color(val)?.to_owned())
+                }),
+                ..update
+            },
+            "order" => Update {
+                order: Some(parse_i64_or_null(val)?),
+                ..update
+            },
+            "error" if val.len() > MAX_ERROR_LEN => {
+                return Err(anyhow!("error must be at most {} bytes", MAX_ERROR_LEN))
+            }
+            "error" => Update {
+                error: Some(if null || val.is_empty() {
+                    None
+                } else {
+                    Some(val.to_owned())
+                }),
+                ..update
+            },
+            "note" => Update {
+                note: Some(if null || val.is_empty() {
+                    None
+                } else {
+                    Some(check_note(val)?.to_owned())
+                }),
+                ..update
+            },
+            "tag" => Update {
+                tags: Some(if null { None } else { parse_tags(val)? }),
+                ..update
+            },
+            "segments" => Update {
+                segments: Some(parse_segments(val)?),
+                ..update
+            },
+            "duration" => Update {
+                duration: Some(match parse_i64_or_null(val)? {
+                    Some(d) if d <= 0 => return Err(anyhow!("duration must be positive")),
+                    d => d,
+                }),
+                ..update
+            },
+            "weight" => Update {
+                weight: Some(if null || val.is_empty() {
+                    None
+                } else {
+                    match val.parse::<f64>() {
+                        Ok(w) if w > 0.0 && w <= MAX_WEIGHT => Some(w),
+                        _ => {
+                            return Err(anyhow!(
+                                "weight must be a number above 0, at most {}",
+                                MAX_WEIGHT
+                            ))
+                        }
+                    }
+                }),
+                ..update
+            },
+            "persist" => Update {
+                persist: Some(match val {
+                    "1" => Some(1),
+                    "0" | "" => None,
+                    _ if null => None,
+                    _ => return Err(anyhow!("persist must be 1 or 0")),
+                }),
+                ..update
+            },
+            "reverse" => Update {
+                reverse: Some(match val {
+                    "1" => Some(1),
+                    "0" | "" => None,
+                    _ if null => None,
+                    _ => return Err(anyhow!("reverse must be 1 or 0")),
+                }),
+                ..update
+            },
+            "type" => Update {
+                metric: Some(match val {
+                    "" => None,
+                    _ if null => None,
+                    _ => match val.parse()? {
+                        Metric::Progress => None,
+                        Metric::Counter => Some("counter".to_owned()),
+                    },
+                }),
+                ..update
+            },
+            "indeterminate" => Update {
+                indeterminate: Some(match val {
+                    "1" => Some(1),
+                    "0" | "" => None,
+                    _ if null => None,
+                    _ => return Err(anyhow!("indeterminate must be 1 or 0")),
+                }),
+                ..update
+            },
+            _ => return Ok(None),
+        }))
+    }
+}
+
+/// `key[]` or `key[3]` is `key`. Other brackets are part of the name.
+fn strip_array_suffix(key: &str) -> &str {
+    let Some((name, index)) = key.strip_suffix(']').and_then(|k| k.rsplit_once('[')) else {
+        return key;
+    };
+
+    if !name.is_empty() && index.chars().all(|c| c.is_ascii_digit()) {
+        name
+    } else {
+        key
+    }
+}
+
+/// What a redis key behind a `Key` holds, as `/debug` shows it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawEntry {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The string, the list's items, or the key's score for the index.
+    pub value: serde_json::Value,
+    /// Seconds left, -1 without a TTL.
+    pub ttl: i64,
+}
+
+/// How many of the unparseable keys a strict scan logs.
+const SCAN_SAMPLE: usize = 5;
+
+/// What the redis keys of the old layout a SCAN returned are: the keys behind
+/// them, each once, each redis key with the name it's renamed to, and the
+/// redis keys that aren't the param of any key.
+type Scanned<'a> = (Vec<Key>, Vec<(&'a str, String)>, Vec<&'a str>);
+
+fn parse_scanned(redis_keys: &[String]) -> Scanned<'_> {
+    let mut found: Vec<Key> = vec![];
+    let mut renames = vec![];
+    let mut unparseable = vec![];
+
+    for redis_key in redis_keys {
+        match Key::from_legacy_redis_key(redis_key) {
+            Ok((key, param)) => {
+                renames.push((redis_key.as_str(), key.redis_key(param)));
+
+                if !found.contains(&key) {
+                    found.push(key);
+                }
+            }
+            Err(_) => unparseable.push(redis_key.as_str()),
+        }
+    }
+
+    (found, renames, unparseable)
+}
+
+/// Appends a history point to `key`'s history, keeping the last
+/// `HISTORY_LEN` points for `ttl`.
+fn history_cmds(pipe: &mut redis::Pipeline, key: &Key, t: i64, current: i64, ttl: usize) {
+    let hkey = key.redis_key("history");
+
+    pipe.rpush(&hkey, HistoryPoint { t, current }.to_redis())
+        .ignore()
+        .ltrim(&hkey, -(HISTORY_LEN as isize), -1)
+        .ignore()
+        .expire(&hkey, ttl)
+        .ignore();
+}
+
+/// How many redis keys `Store::gc` scans, and deletes, at a time.
+const GC_BATCH: usize = 500;
+
+/// What `Store::gc` found under `pcafe:`, by redis key.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct GcReport {
+    pub scanned: usize,
+    /// Not the param of any key, like the keys of an older layout that
+    /// `Store::migrate` hasn't moved.
+    pub unparseable: usize,
+    /// The param of a key, but not one of `KEY_PARAMS`.
+    pub unknown_param: usize,
+    /// Without a TTL, of a key that isn't in its token's index, so nothing
+    /// ever lists or expires it.
+    pub orphaned: usize,
+    /// Left in place with `?dryrun=1`.
+    pub deleted: usize,
+}
+
+/// What `Store::migrate` found of the layout before tokens were hash-tagged.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct MigrateReport {
+    pub scanned: usize,
+    /// Params under the old names, moved unless `?dryrun=1`.
+    pub moved: usize,
+    /// Keys those are params of, indexed once they're moved.
+    pub keys: usize,
+}
+
+/// What's wrong with a redis key that `Store::gc` deletes for its name alone.
+#[derive(Debug, PartialEq)]
+enum Garbage {
+    Unparseable,
+    UnknownParam,
+}
+
+/// The key a scanned redis key is a param of, if it's one that's stored.
+fn gc_parse(redis_key: &str) -> Result<Key, Garbage> {
+    let key = Key::from_redis_key(redis_key).map_err(|_| Garbage::Unparseable)?;
+    let param = redis_key.rsplit(':').next().unwrap_or_default();
+
+    match KEY_PARAMS.contains(&param) {
+        true => Ok(key),
+        false => Err(Garbage::UnknownParam),
+    }
+}
+
+/// Names each field instead of `Update::new`'s positional nested options:
+/// `Update::builder(key).state("compiling").current(5).max(10).build()`.
+/// Whatever isn't mentioned is left as it is stored.
+pub struct UpdateBuilder(Update);
+
+impl UpdateBuilder {
+    pub fn state(self, state: impl Into<String>) -> UpdateBuilder {
+        UpdateBuilder(Update {
+            state: Some(Some(state.into())),
+            ..self.0
+        })
+    }
+
+    pub fn clear_state(self) -> UpdateBuilder {
+        UpdateBuilder(Update {
+            state: Some(None),
+            ..self.0
+        })
+    }
+
+    pub fn current(self, current: i64) -> UpdateBuilder {
+        UpdateBuilder(Update {
+            current: Some(Some(current)),
+            increment: None,
+            ..self.0
+        })
+    }
+
+    pub fn clear_current(self) -> UpdateBuilder {
+        UpdateBuilder(Update {
+            current: Some(None),
+            increment: None,
+            ..self.0
+        })
+    }
+
+    pub fn max(self, max: i64) -> UpdateBuilder {
+        UpdateBuilder(Update {
+            max: Some(Some(max)),
+            ..self.0
+        })
+    }
+
+    pub fn clear_max(self) -> UpdateBuilder {
+        UpdateBuilder(Update {
+            max: Some(None),
+            ..self.0
+        })
+    }
+
+    pub fn build(self) -> Update {
+        self.0
+    }
+}
+
+/// A key that got done at `t`, see `Store::get_events`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompletionEvent {
+    pub key: String,
+    pub t: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HistoryPoint {
+    pub t: i64,
+    pub current: i64,
+}
+
+impl HistoryPoint {
+    fn parse(s: &str) -> Option<HistoryPoint> {
+        let (t, current) = s.split_once(':')?;
+
+        Some(HistoryPoint {
+            t: t.parse().ok()?,
+            current: current.parse().ok()?,
+        })
+    }
+
+    fn to_redis(self) -> String {
+        format!("{}:{}", self.t, self.current)
+    }
+}
+
+/// Items per second over the last `RATE_POINTS` of `history`, which evens
+/// out an update or two that came in bursts. `None` until there's a point
+/// before the latest one to go from, or when they're all of the same second.
+pub fn rate_per_sec(history: &[HistoryPoint]) -> Option<f64> {
+    let recent = &history[history.len().saturating_sub(RATE_POINTS)..];
+    let (first, last) = (recent.first()?, recent.last()?);
+    let dt = last.t - first.t;
+
+    if dt <= 0 {
+        return None;
+    }
+
+    Some((last.current - first.current) as f64 / dt as f64)
+}
+
+/// An `Idempotency-Key`, printable ASCII without spaces.
+pub fn check_idempotency_key(s: &str) -> Result<&str> {
+    if s.is_empty() || s.len() > MAX_IDEMPOTENCY_KEY_LEN || !s.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(anyhow!(
+            "Idempotency-Key must be 1 to {} printable characters",
+            MAX_IDEMPOTENCY_KEY_LEN
+        ));
+    }
+
+    Ok(s)
+}
+
+/// What `Store::claim_send` found for an `Idempotency-Key`.
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+    /// Nothing yet, the send goes ahead.
+    Claimed,
+    /// An identical send that's still being written.
+    Pending,
+    /// An identical send was made, this is what it answered.
+    Sent(String),
+    /// The key was used for a different send.
+    Conflict,
+}
+
+impl Claim {
+    /// A record is the send's fingerprint, a newline, and the answer once
+    /// there is one.
+    fn of_record(record: &str, fingerprint: &str) -> Claim {
+        match record.split_once('\n') {
+            Some((f, _)) if f != fingerprint => Claim::Conflict,
+            Some((_, "")) => Claim::Pending,
+            Some((_, body)) => Claim::Sent(body.to_owned()),
+            None => Claim::Conflict,
+        }
+    }
+}
+
+/// Summed current and max across `values`. Keys without a positive max can't
+/// be placed on the scale and are left out. A `reverse` key counts what's
+/// gone from its max, which is none of it before it has a current.
+fn known_totals<'a>(values: impl IntoIterator<Item = &'a Value>) -> (i64, i64) {
+    values
+        .into_iter()
+        .filter(|v| !v.indeterminate && v.metric.is_progress())
+        .filter_map(|v| match (v.max, v.current) {
+            (Some(m), Some(c)) if m > 0 && v.reverse => Some((m - c.clamp(0, m), m)),
+            (Some(m), None) if m > 0 && v.reverse => Some((0, m)),
+            (Some(m), c) if m > 0 => Some((c.unwrap_or(0).clamp(0, m), m)),
+            _ => None,
+        })
+        .fold((0, 0), |(c, m), (vc, vm)| (c + vc, m + vm))
+}
+
+fn percent_of((current, max): (i64, i64)) -> Option<f64> {
+    if max > 0 {
+        Some(current as f64 * 100.0 / max as f64)
+    } else {
+        None
+    }
+}
+
+/// Decimal places a percent is shown with, from `0` to `3`.
+pub fn parse_precision(s: &str) -> Result<u8> {
+    match s.trim().parse() {
+        Ok(p @ 0..=3) => Ok(p),
+        _ => Err(anyhow!("precision must be 0 to 3")),
+    }
+}
+
+/// `percent` cut down to `precision` decimals. Cut rather than rounded, so
+/// nothing shows 100 before it's done. The epsilon keeps `0.29` from being
+/// cut to `0.28` by float error.
+pub fn round_percent(percent: f64, precision: u8) -> f64 {
+    let scale = 10f64.powi(precision.into());
+
+    (percent * scale + 1e-9).floor() / scale
+}
+
+/// A percent as it's shown everywhere, without the `%`: `41` or `41.7`.
+pub fn format_percent(percent: f64, precision: u8) -> String {
+    format!("{:.*}", precision.into(), round_percent(percent, precision))
+}
+
+/// Overall percent across `values`: the average of their percents, each
+/// weighted by `Value::weight`. Keys without a percent, like those with an
+/// unknown max, are left out along with their weight.
+pub fn overall_percent<'a>(values: impl IntoIterator<Item = &'a Value>) -> Option<f64> {
+    let (sum, weights) = values
+        .into_iter()
+        .filter_map(|v| Some((v.percent()?, v.weight())))
+        .fold((0.0, 0.0), |(sum, weights), (p, w)| {
+            (sum + p * w, weights + w)
+        });
+
+    if weights > 0.0 {
+        Some(sum / weights)
+    } else {
+        None
+    }
+}
+
+/// Keys named `group:rest` belong to `group`.
+pub fn group_of(key: &Key) -> Option<&str> {
+    key.key.split_once(':').map(|(group, _)| group)
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GroupRollup {
+    pub keys: usize,
+    pub current: i64,
+    pub max: i64,
+    pub percent: Option<f64>,
+}
+
+/// One rollup per top-level group, summed like `overall_percent`: keys with an
+/// unknown max count towards `keys` but not towards current, max or percent.
+pub fn group_rollups<'a>(
+    values: impl IntoIterator<Item = (&'a Key, &'a Value)>,
+) -> BTreeMap<String, GroupRollup> {
+    let mut groups: BTreeMap<String, Vec<&Value>> = BTreeMap::new();
+
+    for (key, value) in values {
+        if let Some(group) = group_of(key) {
+            groups.entry(group.to_owned()).or_default().push(value);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(group, values)| (group, rollup_of(&values)))
+        .collect()
+}
+
+/// Like `group_rollups`, but for the groups at every depth: a key
+/// `import:users:fetch` counts towards both `import` and `import:users`.
+pub fn nested_rollups<'a>(
+    values: impl IntoIterator<Item = (&'a Key, &'a Value)>,
+) -> BTreeMap<String, GroupRollup> {
+    let mut groups: BTreeMap<String, Vec<&Value>> = BTreeMap::new();
+
+    for (key, value) in values {
+        let ends = key.key.match_indices(':').map(|(i, _)| i);
+
+        for end in ends {
+            groups
+                .entry(key.key[..end].to_owned())
+                .or_default()
+                .push(value);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(group, values)| (group, rollup_of(&values)))
+        .collect()
+}
+
+fn rollup_of(values: &[&Value]) -> GroupRollup {
+    let (current, max) = known_totals(values.iter().copied());
+
+    GroupRollup {
+        keys: values.len(),
+        current,
+        max,
+        percent: overall_percent(values.iter().copied()),
+    }
+}
+
+/// State words that mark a key as failed, see `Value::is_failed`.
+const FAILURE_KEYWORDS: [&str; 6] = ["error", "errored", "fail", "failed", "failure", "crashed"];
+
+/// Keys counted by how they're doing, for `/summary/{token}.json`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct StatusSummary {
+    pub running: usize,
+    pub done: usize,
+    pub failed: usize,
+    pub total: usize,
+    pub percent: Option<f64>,
+    /// The same counts over just the keys with each tag. A key with several
+    /// tags counts under each of them.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, StatusSummary>,
+}
+
+/// Every key is exactly one of done, failed or running, in that order.
+pub fn summarize<'a>(values: impl IntoIterator<Item = &'a Value>) -> StatusSummary {
+    let values = Vec::from_iter(values);
+    let mut summary = count_statuses(&values);
+
+    for v in &values {
+        for tag in &v.tags {
+            if !summary.tags.contains_key(tag) {
+                let tagged = values.iter().copied().filter(|v| v.has_tag(tag));
+                summary
+                    .tags
+                    .insert(tag.clone(), count_statuses(&Vec::from_iter(tagged)));
+            }
+        }
+    }
+
+    summary
+}
+
+fn count_statuses(values: &[&Value]) -> StatusSummary {
+    let mut summary = StatusSummary {
+        total: values.len(),
+        percent: overall_percent(values.iter().copied()),
+        ..StatusSummary::default()
+    };
+
+    for v in values {
+        if v.is_done() {
+            summary.done += 1;
+        } else if v.is_failed() {
+            summary.failed += 1;
+        } else {
+            summary.running += 1;
+        }
+    }
+
+    summary
+}
+
+/// Averages adjacent pairs in the older half of `points`. Applied whenever the
+/// list outgrows `HISTORY_LEN`, older points end up in ever coarser buckets
+/// while the list keeps spanning the whole run.
+pub fn downsample(points: &[HistoryPoint]) -> Vec<HistoryPoint> {
+    let (older, newer) = points.split_at(points.len() / 2);
+
+    older
+        .chunks(2)
+        .map(|c| HistoryPoint {
+            t: c.iter().map(|p| p.t).sum::<i64>() / c.len() as i64,
+            current: c.iter().map(|p| p.current).sum::<i64>() / c.len() as i64,
+        })
+        .chain(newer.iter().copied())
+        .collect()
+}
+
+/// What the routes need of a `Store`'s connection: one they can clone into
+/// every handler and hold across awaits.
+pub trait Connection:
+    redis::aio::ConnectionLike + AsyncCommands + Clone + Send + Sync + 'static
+{
+}
+
+impl<C> Connection for C where
+    C: redis::aio::ConnectionLike + AsyncCommands + Clone + Send + Sync + 'static
+{
+}
+
+/// A connection that refuses every command, for testing the routes in front
+/// of a store: whatever gets past them answers like Redis is down.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct NoRedis;
+
+#[cfg(test)]
+impl redis::aio::ConnectionLike for NoRedis {
+    fn req_packed_command<'a>(&'a mut self, _: &'a Cmd) -> redis::RedisFuture<'a, redis::Value> {
+        Box::pin(async { Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()) })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _: &'a redis::Pipeline,
+        _: usize,
+        _: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async { Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()) })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[derive(Clone)]
+pub struct Store<C: redis::aio::ConnectionLike + AsyncCommands + Clone> {
+    redis: C,
+    downsample_history: bool,
+    legacy_scan: bool,
+    persist_done: bool,
+    strict_scan: bool,
+    /// Shared by all clones, so `set_expire_seconds` applies to every one.
+    expire_seconds: Arc<AtomicUsize>,
+    /// Redis keys a SCAN found under `pcafe:` that aren't ours, see `with_strict_scan`.
+    unparseable: Arc<AtomicUsize>,
+}
+
+impl<C: redis::aio::ConnectionLike + AsyncCommands + Clone> Store<C> {
+    pub fn new(redis: C) -> Store<C> {
+        Store {
+            redis,
+            downsample_history: false,
+            legacy_scan: false,
+            persist_done: false,
+            strict_scan: false,
+            expire_seconds: Arc::new(AtomicUsize::new(EXPIRE_SECONDS)),
+            unparseable: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// TTL of everything written from now on, and of keys touched.
+    pub fn set_expire_seconds(&self, secs: usize) {
+        self.expire_seconds.store(secs, Ordering::Relaxed);
+    }
+
+    fn ttl(&self) -> usize {
+        self.expire_seconds.load(Ordering::Relaxed)
+    }
+
+    /// The TTL `update` writes its key with.
+    fn ttl_of(&self, update: &Update) -> usize {
+        update.ttl.unwrap_or_else(|| self.ttl())
+    }
+
+    /// Whether a token without an index is looked for with SCAN, see
+    /// `get_all_keys`.
+    pub fn with_legacy_scan(self, legacy_scan: bool) -> Store<C> {
+        Store {
+            legacy_scan,
+            ..self
+        }
+    }
+
+    /// Keep every key without a TTL once it's done, not just the ones sent
+    /// with `.persist=1`. Persisted keys stay until they're deleted, so the
+    /// keyspace keeps growing with every finished key unless something clears
+    /// them. Writing to or touching a persisted key gives it a TTL again,
+    /// until it's done again.
+    pub fn with_persist_done(self, persist_done: bool) -> Store<C> {
+        Store {
+            persist_done,
+            ..self
+        }
+    }
+
+    /// Log the redis keys a SCAN finds under a token that don't parse as
+    /// params of a key, with a few of them as a sample, instead of just
+    /// skipping them. They're counted either way, see `unparseable_keys`.
+    pub fn with_strict_scan(self, strict_scan: bool) -> Store<C> {
+        Store {
+            strict_scan,
+            ..self
+        }
+    }
+
+    /// How many redis keys SCANs skipped since startup, for schema drift or
+    /// another app writing under `pcafe:`. A key found by several scans
+    /// counts each time.
+    pub fn unparseable_keys(&self) -> usize {
+        self.unparseable.load(Ordering::Relaxed)
+    }
+
+    /// Compact the history by downsampling instead of dropping the oldest points.
+    /// Compaction rewrites the list, so concurrent writers to one key may lose a point.
+    pub fn with_downsampled_history(self, downsample_history: bool) -> Store<C> {
+        Store {
+            downsample_history,
+            ..self
+        }
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut self.redis.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Indexes `key` for `ttl` seconds. What's shared by the token's keys,
+    /// the index itself, the meta and the events, is kept for at least the
+    /// default TTL and never for less than it has left, so one key given a
+    /// shorter one doesn't cut them short.
+    fn index_cmds(&self, pipe: &mut redis::Pipeline, key: &Key, ttl: usize) {
+        pipe.zadd(
+            Key::index_key(&key.token),
+            &key.key,
+            unix_now() + ttl as i64,
+        )
+        .ignore();
+
+        self.shared_ttl_cmds(pipe, &key.token, ttl);
+    }
+
+    /// The part of `index_cmds` for what the token's keys share.
+    fn shared_ttl_cmds(&self, pipe: &mut redis::Pipeline, token: &str, ttl: usize) {
+        let shared_ttl = ttl.max(self.ttl());
+
+        pipe.cmd("EVAL")
+            .arg(EXTEND_EXPIRE)
+            .arg(2)
+            .arg(Key::meta_key(token))
+            .arg(Key::events_key(token))
+            .arg(shared_ttl)
+            .ignore()
+            .cmd("EVAL")
+            .arg(EXPIRE_INDEX)
+            .arg(1)
+            .arg(Key::index_key(token))
+            .arg(shared_ttl)
+            .ignore();
+    }
+
+    /// See `PERSIST_IF_DONE`.
+    fn persist_if_done_cmd(&self, key: &Key) -> Cmd {
+        let mut cmd = redis::cmd("EVAL");
+
+        cmd.arg(PERSIST_IF_DONE).arg(12 + KEY_PARAMS.len());
+
+        for param in ["state", "current", "max", "error", "persist"] {
+            cmd.arg(key.redis_key(param));
+        }
+
+        cmd.arg(Key::index_key(&key.token))
+            .arg(Key::frozen_key(&key.token))
+            .arg(Key::meta_key(&key.token))
+            .arg(Key::events_key(&key.token))
+            .arg(key.redis_key("indeterminate"))
+            .arg(key.redis_key("reverse"))
+            .arg(key.redis_key("type"))
+            .arg(&KEY_PARAMS.map(|p| key.redis_key(p))[..])
+            .arg(&key.key)
+            .arg(if self.persist_done { "1" } else { "0" })
+            .arg(DONE_PERCENT.load(Ordering::Relaxed));
+
+        cmd
+    }
+
+    /// See `RECORD_DONE`, it goes before `persist_if_done_cmd` so that a
+    /// persisted key's `done_at` stays persisted.
+    fn record_done_cmd(&self, key: &Key) -> Cmd {
+        let mut cmd = redis::cmd("EVAL");
+
+        cmd.arg(RECORD_DONE).arg(9);
+
+        for param in ["state", "current", "max", "error", "done_at"] {
+            cmd.arg(key.redis_key(param));
+        }
+
+        cmd.arg(Key::events_key(&key.token))
+            .arg(key.redis_key("indeterminate"))
+            .arg(key.redis_key("reverse"))
+            .arg(key.redis_key("type"))
+            .arg(&key.key)
+            .arg(unix_now())
+            .arg(self.ttl())
+            .arg(EVENTS_LEN)
+            .arg(DONE_PERCENT.load(Ordering::Relaxed));
+
+        cmd
+    }
+
+    /// What runs after a write to `key`, once its params are in place.
+    fn after_write_cmds(&self, key: &Key) -> [Cmd; 2] {
+        [self.record_done_cmd(key), self.persist_if_done_cmd(key)]
+    }
+
+    async fn index(&self, keys: &[&Key], ttl: usize) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+
+        for key in keys {
+            self.index_cmds(&mut pipe, key, ttl);
+        }
+
+        Ok(pipe.query_async(&mut self.redis.clone()).await?)
+    }
+
+    pub async fn update(&self, update: &Update) -> Result<()> {
+        self.update_many(std::slice::from_ref(update)).await
+    }
+
+    /// Writes all of `updates`, in order, with one MULTI/EXEC for every param,
+    /// increment, TTL, index entry and history point, and for recording and
+    /// persisting the keys that are now done: one round trip for the batch.
+    /// An increment's point is taken from the current it left by `PUSH_CURRENT`.
+    /// With downsampled history each key's history is read and rewritten on
+    /// its own after the transaction, and the done keys handled after that.
+    ///
+    /// Redis runs nothing of a transaction it doesn't get the EXEC of, so a
+    /// connection lost midway leaves every key as it was, and a transaction
+    /// that ran wrote all of it. Only a downsampled history can be missing.
+    pub async fn update_many(&self, updates: &[Update]) -> Result<()> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        let t = unix_now();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for u in updates {
+            let ttl = self.ttl_of(u);
+
+            for c in u.as_cmds(ttl) {
+                pipe.add_command(c).ignore();
+            }
+
+            if let Some(delta) = u.increment {
+                pipe.add_command(self.increment_cmd(u, delta));
+            }
+
+            if let Some(max) = u.set_max {
+                pipe.add_command(self.set_max_cmd(u, max)).ignore();
+            }
+
+            self.index_cmds(&mut pipe, &u.key, ttl);
+
+            if self.downsample_history {
+                continue;
+            }
+
+            match (u.increment, u.current.flatten()) {
+                (Some(_), _) => {
+                    pipe.cmd("EVAL")
+                        .arg(PUSH_CURRENT)
+                        .arg(2)
+                        .arg(u.key.redis_key("current"))
+                        .arg(u.key.redis_key("history"))
+                        .arg(t)
+                        .arg(HISTORY_LEN)
+                        .arg(ttl)
+                        .ignore();
+                }
+                (None, Some(current)) => history_cmds(&mut pipe, &u.key, t, current, ttl),
+                (None, None) => {}
+            }
+
+            for cmd in self.after_write_cmds(&u.key) {
+                pipe.add_command(cmd).ignore();
+            }
+        }
+
+        let incremented: Vec<i64> = pipe.query_async(&mut self.redis.clone()).await?;
+
+        if !self.downsample_history {
+            return Ok(());
+        }
+
+        let mut incremented = incremented.into_iter();
+
+        let points: Vec<(&Key, i64, usize)> = updates
+            .iter()
+            .filter_map(|u| {
+                let current = match u.increment {
+                    Some(_) => incremented.next(),
+                    None => u.current.flatten(),
+                };
+
+                Some((&u.key, current?, self.ttl_of(u)))
+            })
+            .collect();
+
+        let after = updates
+            .iter()
+            .flat_map(|u| self.after_write_cmds(&u.key))
+            .collect();
+
+        self.push_histories(&points, after).await
+    }
+
+    /// See `INCREMENT`, run as a plain EVAL so it can go in a pipeline.
+    fn increment_cmd(&self, update: &Update, delta: i64) -> Cmd {
+        let mut cmd = redis::cmd("EVAL");
+
+        cmd.arg(INCREMENT)
+            .arg(3)
+            .arg(update.key.redis_key("current"))
+            .arg(update.key.redis_key("max"))
+            .arg(update.key.redis_key("created_at"))
+            .arg(delta)
+            .arg(self.ttl_of(update))
+            .arg(unix_now())
+            .arg(match update.max {
+                None => String::new(),
+                Some(None) => "-".to_owned(),
+                Some(Some(m)) => m.to_string(),
+            });
+
+        cmd
+    }
+
+    fn set_max_cmd(&self, update: &Update, max: i64) -> Cmd {
+        let mut cmd = redis::cmd("EVAL");
+
+        cmd.arg(SET_MAX)
+            .arg(2)
+            .arg(update.key.redis_key("max"))
+            .arg(update.key.redis_key("current"))
+            .arg(max)
+            .arg(self.ttl_of(update));
+
+        cmd
+    }
+
+    /// Like `update`, but skipped when a write with a newer `timestamp` has
+    /// already been applied to the key. Producers with skewed clocks or
+    /// reordered requests can't roll a key back this way. Returns whether the
+    /// update was applied.
+    pub async fn update_if_newer(&self, update: &Update, timestamp: i64) -> Result<bool> {
+        let applied = self
+            .update_many_if_newer(std::slice::from_ref(update), timestamp)
+            .await?;
+
+        Ok(applied[0])
+    }
+
+    /// `update_if_newer` for each of `updates`, all in one MULTI/EXEC like
+    /// `update_many`, returning which were applied.
+    pub async fn update_many_if_newer(
+        &self,
+        updates: &[Update],
+        timestamp: i64,
+    ) -> Result<Vec<bool>> {
+        for u in updates {
+            if u.increment.is_some() {
+                return Err(anyhow!("increments can't be combined with ts"));
+            }
+
+            if u.set_max.is_some() {
+                return Err(anyhow!("setmax can't be combined with ts"));
+            }
+        }
+
+        self.write_if(updates, |u, now| {
+            let mut cmd = redis::cmd("EVAL");
+
+            cmd.arg(SET_IF_NEWER)
+                .arg(5 + u.changes().len())
+                .arg(u.key.redis_key("ts"))
+                .arg(u.key.redis_key("created_at"))
+                .arg(u.key.redis_key("updated_at"))
+                .arg(Key::index_key(&u.key.token))
+                .arg(u.key.redis_key("history"));
+
+            for (param, _) in u.changes() {
+                cmd.arg(u.key.redis_key(param));
+            }
+
+            cmd.arg(timestamp);
+            self.condition_args(&mut cmd, u, now);
+            cmd
+        })
+        .await
+    }
+
+    /// Like `update`, but only if nothing is stored for the key yet, see
+    /// `INIT_IF_ABSENT`. Returns whether the key was created.
+    pub async fn init_if_absent(&self, update: &Update) -> Result<bool> {
+        let created = self
+            .init_many_if_absent(std::slice::from_ref(update))
+            .await?;
+
+        Ok(created[0])
+    }
+
+    /// `init_if_absent` for each of `updates`, all in one MULTI/EXEC like
+    /// `update_many`, returning which were created.
+    pub async fn init_many_if_absent(&self, updates: &[Update]) -> Result<Vec<bool>> {
+        for u in updates {
+            if u.increment.is_some() {
+                return Err(anyhow!("increments can't be combined with init"));
+            }
+
+            if u.set_max.is_some() {
+                return Err(anyhow!("setmax can't be combined with init"));
+            }
+        }
+
+        self.write_if(updates, |u, now| {
+            let others = VALUE_PARAMS
+                .iter()
+                .filter(|p| !["created_at", "updated_at"].contains(p));
+            let mut cmd = redis::cmd("EVAL");
+
+            cmd.arg(INIT_IF_ABSENT)
+                .arg(VALUE_PARAMS.len() + 2 + u.changes().len())
+                .arg(u.key.redis_key("created_at"))
+                .arg(u.key.redis_key("updated_at"));
+
+            for param in others {
+                cmd.arg(u.key.redis_key(param));
+            }
+
+            cmd.arg(Key::index_key(&u.key.token))
+                .arg(u.key.redis_key("history"));
+
+            for (param, _) in u.changes() {
+                cmd.arg(u.key.redis_key(param));
+            }
+
+            cmd.arg(VALUE_PARAMS.len());
+            self.condition_args(&mut cmd, u, now);
+            cmd
+        })
+        .await
+    }
+
+    /// The ARGV `SET_IF_NEWER` and `INIT_IF_ABSENT` share after their first.
+    fn condition_args(&self, cmd: &mut Cmd, update: &Update, now: i64) {
+        // A downsampled history is rewritten outside of the script, see `write_if`
+        let point = match update.current {
+            Some(Some(current)) if !self.downsample_history => current.to_string(),
+            _ => String::new(),
+        };
+
+        cmd.arg(self.ttl_of(update))
+            .arg(now)
+            .arg(&update.key.key)
+            .arg(HISTORY_LEN)
+            .arg(point);
+
+        for (_, val) in update.changes() {
+            cmd.arg(match val {
+                Some(v) => format!("={}", v),
+                None => "-".to_owned(),
+            });
+        }
+    }
+
+    /// Writes each of `updates` with the script `condition` builds, which
+    /// decides whether it's written and writes its index entry and history
+    /// point if so, then what's shared by the token's keys and the done
+    /// checks, all in one MULTI/EXEC. Returns what each script decided.
+    /// With downsampled history the points of the written keys are pushed
+    /// one by one after the transaction, and may be missing.
+    async fn write_if(
+        &self,
+        updates: &[Update],
+        condition: impl Fn(&Update, i64) -> Cmd,
+    ) -> Result<Vec<bool>> {
+        if updates.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let now = unix_now();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for u in updates {
+            pipe.add_command(condition(u, now));
+            self.shared_ttl_cmds(&mut pipe, &u.key.token, self.ttl_of(u));
+
+            for cmd in self.after_write_cmds(&u.key) {
+                pipe.add_command(cmd).ignore();
+            }
+        }
+
+        let written: Vec<bool> = pipe.query_async(&mut self.redis.clone()).await?;
+
+        if self.downsample_history {
+            for (u, _) in updates.iter().zip(&written).filter(|(_, w)| **w) {
+                if let Some(Some(current)) = u.current {
+                    self.push_history(&u.key, current, self.ttl_of(u)).await?;
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Appends `points` to their keys' history, each expiring with its TTL,
+    /// then runs `then`.
+    async fn push_histories(&self, points: &[(&Key, i64, usize)], then: Vec<Cmd>) -> Result<()> {
+        if points.is_empty() && then.is_empty() {
+            return Ok(());
+        }
+
+        let t = unix_now();
+        let mut pipe = redis::pipe();
+
+        if self.downsample_history {
+            for (key, current, ttl) in points {
+                self.push_history(key, *current, *ttl).await?;
+            }
+        }
+
+        for (key, current, ttl) in points.iter().filter(|_| !self.downsample_history) {
+            history_cmds(&mut pipe, key, t, *current, *ttl);
+        }
+
+        for cmd in then {
+            pipe.add_command(cmd).ignore();
+        }
+
+        Ok(pipe.query_async(&mut self.redis.clone()).await?)
+    }
+
+    async fn push_history(&self, key: &Key, current: i64, ttl: usize) -> Result<()> {
+        let hkey = key.redis_key("history");
+        let point = HistoryPoint {
+            t: unix_now(),
+            current,
+        };
+        let mut redis = self.redis.clone();
+
+        redis.rpush::<_, _, ()>(&hkey, point.to_redis()).await?;
+
+        if !self.downsample_history {
+            redis
+                .ltrim::<_, ()>(&hkey, -(HISTORY_LEN as isize), -1)
+                .await?;
+        } else {
+            let points = self.get_history(key).await?;
+
+            if points.len() > HISTORY_LEN {
+                let compacted: Vec<String> = downsample(&points)
+                    .into_iter()
+                    .map(HistoryPoint::to_redis)
+                    .collect();
+
+                redis::pipe()
+                    .atomic()
+                    .del(&hkey)
+                    .rpush(&hkey, compacted)
+                    .query_async::<_, ()>(&mut redis)
+                    .await?;
+            }
+        }
+
+        redis.expire::<_, ()>(&hkey, ttl).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_history(&self, key: &Key) -> Result<Vec<HistoryPoint>> {
+        let raw: Vec<String> = self
+            .redis
+            .clone()
+            .lrange(key.redis_key("history"), 0, -1)
+            .await?;
+
+        Ok(raw.iter().filter_map(|s| HistoryPoint::parse(s)).collect())
+    }
+
+    /// `get_history` of each of `keys`, in one pipeline.
+    pub async fn get_histories(&self, keys: &[Key]) -> Result<Vec<Vec<HistoryPoint>>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pipe = redis::pipe();
+
+        for key in keys {
+            pipe.lrange(key.redis_key("history"), 0, -1);
+        }
+
+        let raw: Vec<Vec<String>> = pipe.query_async(&mut self.redis.clone()).await?;
+
+        Ok(raw
+            .iter()
+            .map(|points| {
+                points
+                    .iter()
+                    .filter_map(|s| HistoryPoint::parse(s))
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// When the token's keys got done, oldest first, the last `EVENTS_LEN`
+    /// of them. Like the token's meta, the list expires a TTL after the
+    /// token's last write.
+    pub async fn get_events(&self, token: &str) -> Result<Vec<CompletionEvent>> {
+        let raw: Vec<String> = self
+            .redis
+            .clone()
+            .lrange(Key::events_key(check_token(token)?), 0, -1)
+            .await?;
+
+        Ok(raw
+            .iter()
+            .filter_map(|s| {
+                let (t, key) = s.split_once(':')?;
+
+                Some(CompletionEvent {
+                    key: key.to_owned(),
+                    t: t.parse().ok()?,
+                })
+            })
+            .collect())
+    }
+
+    /// Keeps the key names a diff poll answered with under `hash`, for the
+    /// next poll to see which of them are gone. The same names always have
+    /// the same hash, so a token has one of these per set of keys it's had,
+    /// each expiring like the keys do.
+    pub async fn save_key_set(&self, token: &str, hash: &str, names: &[&str]) -> Result<()> {
+        let key_set = Key::key_set_key(check_token(token)?, hash);
+
+        redis::pipe()
+            .cmd("SET")
+            .arg(&key_set)
+            .arg(names.join(","))
+            .arg("NX")
+            .arg("EX")
+            .arg(self.ttl())
+            .ignore()
+            .expire(&key_set, self.ttl())
+            .ignore()
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// See `save_key_set`, `None` once it expired or for a hash never saved.
+    pub async fn get_key_set(&self, token: &str, hash: &str) -> Result<Option<Vec<String>>> {
+        let names: Option<String> = self
+            .redis
+            .clone()
+            .get(Key::key_set_key(check_token(token)?, hash))
+            .await?;
+
+        Ok(names.map(|n| {
+            n.split(',')
+                .filter(|n| !n.is_empty())
+                .map(str::to_owned)
+                .collect()
+        }))
+    }
+
+    pub async fn get_state(&self, key: &Key) -> Result<Value> {
+        let mut states = self.get_states(std::slice::from_ref(key)).await?;
+
+        Ok(states.remove(0))
+    }
+
+    /// Names in the token's index that haven't expired yet, dropping the
+    /// ones that have.
+    async fn indexed(&self, token: &str) -> Result<Vec<String>> {
+        let index = Key::index_key(check_token(token)?);
+
+        let (names,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .zrembyscore(&index, "-inf", unix_now())
+            .ignore()
+            .zrange(&index, 0, -1)
+            .query_async(&mut self.redis.clone())
+            .await?;
+
+        Ok(names)
+    }
+
+    /// Keys of the token under `keyprefix`, from its index in the
+    /// connection's DB.
+    ///
+    /// A token without an index may still have keys written before there was
+    /// one, under the old unhash-tagged names. With `with_legacy_scan(true)`
+    /// those are looked for with SCAN MATCH, moved and put in the index.
+    /// That's a scan of the whole keyspace for every look at an empty or
+    /// unknown token, which anyone can ask for, so it's off unless asked for
+    /// until `Store::migrate` has moved everything. Like any SCAN it only
+    /// covers one node of a cluster.
+    pub async fn get_all_keys(&self, token: &str, keyprefix: &str) -> Result<HashSet<Key>> {
+        let mut names = self.indexed(token).await?;
+
+        if names.is_empty() && self.legacy_scan {
+            names = self.scan_legacy(token).await?;
+        }
+
+        Ok(names
+            .into_iter()
+            .filter(|name| name.starts_with(keyprefix))
+            .map(|key| Key {
+                token: token.to_owned(),
+                key,
+            })
+            .collect())
+    }
+
+    /// Sets or deletes fields of the token's meta. It expires like the token's
+    /// keys, every write to a key refreshes it, and none cuts it short.
+    pub async fn set_meta(
+        &self,
+        token: &str,
+        changes: &[(&'static str, Option<String>)],
+    ) -> Result<()> {
+        let meta = Key::meta_key(check_token(token)?);
+        let mut pipe = redis::pipe();
+
+        for (field, val) in changes {
+            match val {
+                Some(v) => pipe.hset(&meta, field, v).ignore(),
+                None => pipe.hdel(&meta, field).ignore(),
+            };
+        }
+
+        pipe.cmd("EVAL")
+            .arg(EXTEND_EXPIRE)
+            .arg(1)
+            .arg(&meta)
+            .arg(self.ttl())
+            .ignore()
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Claims `idem` for a send of `fingerprint` to `token` unless an earlier
+    /// send already did, in which case it's what that one left. The claim is
+    /// kept for `IDEMPOTENCY_SECONDS`, see `finish_send` and `release_send`.
+    pub async fn claim_send(&self, token: &str, idem: &str, fingerprint: &str) -> Result<Claim> {
+        let key = Key::idempotency_key(check_token(token)?, check_idempotency_key(idem)?);
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(format!("{}\n", fingerprint))
+            .arg("NX")
+            .arg("EX")
+            .arg(IDEMPOTENCY_SECONDS)
+            .query_async(&mut self.redis.clone())
+            .await?;
+
+        if set.is_some() {
+            return Ok(Claim::Claimed);
+        }
+
+        let record: Option<String> = self.redis.clone().get(&key).await?;
+
+        // Expired in between, which leaves nothing to repeat
+        Ok(match record {
+            Some(record) => Claim::of_record(&record, fingerprint),
+            None => Claim::Claimed,
+        })
+    }
+
+    /// Keeps what the claimed send answered, for a retry of it to get.
+    pub async fn finish_send(
+        &self,
+        token: &str,
+        idem: &str,
+        fingerprint: &str,
+        body: &str,
+    ) -> Result<()> {
+        redis::cmd("SET")
+            .arg(Key::idempotency_key(
+                check_token(token)?,
+                check_idempotency_key(idem)?,
+            ))
+            .arg(format!("{}\n{}", fingerprint, body))
+            .arg("XX")
+            .arg("EX")
+            .arg(IDEMPOTENCY_SECONDS)
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gives up a claim whose send failed, so a retry isn't turned away.
+    pub async fn release_send(&self, token: &str, idem: &str) -> Result<()> {
+        self.redis
+            .clone()
+            .del::<_, ()>(Key::idempotency_key(
+                check_token(token)?,
+                check_idempotency_key(idem)?,
+            ))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Flags the token as told of its completion, returns whether it wasn't
+    /// already. The flag expires like the token's keys do.
+    pub async fn mark_notified(&self, token: &str) -> Result<bool> {
+        let set: Option<String> = redis::cmd("SET")
+            .arg(Key::notified_key(check_token(token)?))
+            .arg("1")
+            .arg("NX")
+            .arg("EX")
+            .arg(self.ttl())
+            .query_async(&mut self.redis.clone())
+            .await?;
+
+        Ok(set.is_some())
+    }
+
+    /// Clears `mark_notified` once the token isn't complete anymore, so its
+    /// next completion is told too.
+    pub async fn clear_notified(&self, token: &str) -> Result<()> {
+        self.redis
+            .clone()
+            .del::<_, ()>(Key::notified_key(check_token(token)?))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Counts a view of the token's /see page. The count expires like the
+    /// token's keys do, counted from the last view.
+    pub async fn count_view(&self, token: &str) -> Result<()> {
+        let views = Key::views_key(check_token(token)?);
+
+        redis::pipe()
+            .incr(&views, 1)
+            .ignore()
+            .expire(&views, self.ttl())
+            .ignore()
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Points the view token `view` at `token`, for as long as a key lives
+    /// unless it's done again, see `ShareKey::view_token` and
+    /// `REMEMBER_VIEW`.
+    pub async fn remember_view(&self, view: &str, token: &str) -> Result<()> {
+        redis::Script::new(REMEMBER_VIEW)
+            .key(Key::view_key(check_token(view)?))
+            .arg(check_token(token)?)
+            .arg(self.ttl())
+            .invoke_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// The token `remember_view` pointed `view` at, if it's still there.
+    pub async fn view_of(&self, view: &str) -> Result<Option<String>> {
+        Ok(self
+            .redis
+            .clone()
+            .get(Key::view_key(check_token(view)?))
+            .await?)
+    }
+
+    /// How many views `count_view` has counted, 0 for none.
+    pub async fn get_views(&self, token: &str) -> Result<i64> {
+        let views: Option<i64> = self
+            .redis
+            .clone()
+            .get(Key::views_key(check_token(token)?))
+            .await?;
+
+        Ok(views.unwrap_or(0))
+    }
+
+    pub async fn get_meta(&self, token: &str) -> Result<TokenMeta> {
+        let (title, description, strip_prefix) = redis::cmd("HMGET")
+            .arg(Key::meta_key(check_token(token)?))
+            .arg("title")
+            .arg("description")
+            .arg("strip_prefix")
+            .query_async(&mut self.redis.clone())
+            .await?;
+
+        Ok(TokenMeta {
+            title,
+            description,
+            strip_prefix,
+        })
+    }
+
+    /// Deletes what's under `pcafe:` that isn't a live param of a key, see
+    /// `GcReport`, or only counts it when `dry_run`. The keyspace is SCANned
+    /// `GC_BATCH` keys at a time, each batch checked and deleted in a
+    /// pipeline of its own, so redis is never blocked for long. The same
+    /// caveats as the legacy scan apply.
+    pub async fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        let mut cursor = 0u64;
+
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg("pcafe:*")
+                .arg("COUNT")
+                .arg(GC_BATCH)
+                .query_async(&mut self.redis.clone())
+                .await?;
+
+            report.scanned += batch.len();
+
+            let mut garbage = vec![];
+            let mut parsed = vec![];
+
+            for redis_key in &batch {
+                match gc_parse(redis_key) {
+                    Ok(key) => parsed.push((redis_key, key)),
+                    Err(Garbage::Unparseable) => {
+                        report.unparseable += 1;
+                        garbage.push(redis_key);
+                    }
+                    Err(Garbage::UnknownParam) => {
+                        report.unknown_param += 1;
+                        garbage.push(redis_key);
+                    }
+                }
+            }
+
+            if !parsed.is_empty() {
+                let mut pipe = redis::pipe();
+
+                for (redis_key, key) in &parsed {
+                    pipe.pttl(*redis_key)
+                        .zscore(Key::index_key(&key.token), &key.key);
+                }
+
+                let checks: Vec<redis::Value> = pipe.query_async(&mut self.redis.clone()).await?;
+
+                for ((redis_key, _), check) in parsed.iter().zip(checks.chunks(2)) {
+                    let pttl: i64 = redis::from_redis_value(&check[0])?;
+                    let indexed = check[1] != redis::Value::Nil;
+
+                    if pttl == -1 && !indexed {
+                        report.orphaned += 1;
+                        garbage.push(redis_key);
+                    }
+                }
+            }
+
+            if !dry_run && !garbage.is_empty() {
+                let deleted: usize = self.redis.clone().del(&garbage).await?;
+                report.deleted += deleted;
+            }
+
+            cursor = next;
+            if cursor == 0 {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Tokens starting with `prefix` that have an index, sorted. Found with a
+    /// SCAN of the keyspace, so the same caveats as the legacy scan apply.
+    pub async fn get_all_tokens(&self, prefix: &str) -> Result<Vec<String>> {
+        let pattern = format!("pcafe-index:{{{}*", check_string_or_empty(prefix)?);
+
+        let mut tokens = vec![];
+        let mut redis = self.redis.clone();
+        let mut iter = redis.scan_match::<_, String>(pattern).await?;
+
+        while let Some(index) = iter.next_item().await {
+            let token = index
+                .strip_prefix("pcafe-index:{")
+                .and_then(|rest| rest.strip_suffix('}'));
+
+            if let Some(token) = token {
+                tokens.push(token.to_owned());
+            }
+        }
+
+        tokens.sort();
+        tokens.dedup();
+
+        Ok(tokens)
+    }
+
+    /// Every redis key stored for `key`, raw, with its type and TTL: the
+    /// params that exist and the key's entry in the token's index. For
+    /// troubleshooting, two round trips.
+    pub async fn debug_key(&self, key: &Key) -> Result<BTreeMap<String, RawEntry>> {
+        let mut pipe = redis::pipe();
+
+        for param in KEY_PARAMS {
+            pipe.cmd("TYPE")
+                .arg(key.redis_key(param))
+                .ttl(key.redis_key(param));
+        }
+
+        let index = Key::index_key(&key.token);
+        pipe.zscore(&index, &key.key).ttl(&index);
+
+        let kinds: Vec<redis::Value> = pipe.query_async(&mut self.redis.clone()).await?;
+        let (params, index_entry) = kinds.split_at(KEY_PARAMS.len() * 2);
+
+        let mut found = vec![];
+        let mut reads = redis::pipe();
+
+        for (param, pair) in KEY_PARAMS.iter().zip(params.chunks(2)) {
+            let kind: String = redis::from_redis_value(&pair[0])?;
+            let redis_key = key.redis_key(param);
+
+            match kind.as_str() {
+                "none" => continue,
+                "string" => reads.get(&redis_key),
+                "list" => reads.lrange(&redis_key, 0, -1),
+                _ => reads.cmd("ECHO").arg(""),
+            };
+
+            found.push((redis_key, kind, redis::from_redis_value::<i64>(&pair[1])?));
+        }
+
+        let values: Vec<redis::Value> = reads.query_async(&mut self.redis.clone()).await?;
+        let mut entries = BTreeMap::new();
+
+        for ((redis_key, kind, ttl), value) in found.into_iter().zip(values) {
+            let value = match kind.as_str() {
+                "string" => serde_json::json!(redis::from_redis_value::<String>(&value)?),
+                "list" => serde_json::json!(redis::from_redis_value::<Vec<String>>(&value)?),
+                _ => serde_json::Value::Null,
+            };
+
+            entries.insert(redis_key, RawEntry { kind, value, ttl });
+        }
+
+        if let Some(score) = redis::from_redis_value::<Option<String>>(&index_entry[0])? {
+            entries.insert(
+                index,
+                RawEntry {
+                    kind: "zset".to_owned(),
+                    value: serde_json::json!(score),
+                    ttl: redis::from_redis_value(&index_entry[1])?,
+                },
+            );
+        }
+
+        Ok(entries)
+    }
+
+    /// Milliseconds left on each of the token's keys starting with
+    /// `keyprefix`, sorted by key, going by their `updated_at`, which every
+    /// write sets. `None` is a persisted key, and keys that expired meanwhile
+    /// are left out. One PTTL per key, all in one pipeline after listing the
+    /// keys.
+    pub async fn get_all_keys_with_ttl(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> Result<Vec<(Key, Option<i64>)>> {
+        let mut keys = Vec::from_iter(self.get_all_keys(token, keyprefix).await?);
+        keys.sort();
+
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pipe = redis::pipe();
+
+        for key in &keys {
+            pipe.cmd("PTTL").arg(key.redis_key("updated_at"));
+        }
+
+        let pttls: Vec<i64> = pipe.query_async(&mut self.redis.clone()).await?;
+
+        Ok(keys
+            .into_iter()
+            .zip(pttls)
+            .filter_map(|(key, pttl)| match pttl {
+                -2 => None,
+                -1 => Some((key, None)),
+                ms => Some((key, Some(ms))),
+            })
+            .collect())
+    }
+
+    /// The token's keys with their values and TTLs, sorted by key, see
+    /// `ExportedKey`. Keys that expired meanwhile are left out.
+    pub async fn export_token(&self, token: &str) -> Result<Vec<ExportedKey>> {
+        let ttls = self.get_all_keys_with_ttl(token, "").await?;
+        let keys = Vec::from_iter(ttls.iter().map(|(key, _)| key.clone()));
+        let values = self.get_states(&keys).await?;
+
+        Ok(ttls
+            .into_iter()
+            .zip(values)
+            .filter(|(_, value)| !value.is_empty())
+            .map(|((key, pttl), value)| ExportedKey {
+                token: key.token,
+                key: key.key,
+                pttl,
+                value,
+            })
+            .collect())
+    }
+
+    /// Writes exported keys back in place of whatever is stored for them,
+    /// with the TTLs they had left, and indexes them, in one pipeline.
+    /// Returns how many keys were restored.
+    pub async fn restore(&self, exported: &[ExportedKey]) -> Result<usize> {
+        let keys = exported
+            .iter()
+            .map(|e| Key::try_from((e.token.as_str(), e.key.as_str())))
+            .collect::<Result<Vec<_>>>()?;
+        let now = unix_now();
+
+        let mut pipe = redis::pipe();
+        let mut tokens = BTreeSet::new();
+
+        for (key, e) in keys.iter().zip(exported) {
+            pipe.del(KEY_PARAMS.map(|p| key.redis_key(p)).to_vec())
+                .ignore();
+
+            for (param, val) in VALUE_PARAMS.iter().zip(e.value.to_redis()) {
+                let Some(val) = val else {
+                    continue;
+                };
+
+                match e.pttl {
+                    Some(ms) => pipe.pset_ex(key.redis_key(param), val, ms.max(1) as usize),
+                    None => pipe.set(key.redis_key(param), val),
+                }
+                .ignore();
+            }
+
+            // Scored like `index_cmds` and `PERSIST_IF_DONE` would have
+            let score = match e.pttl {
+                Some(ms) => (now + (ms + 999) / 1000).to_string(),
+                None => "+inf".to_owned(),
+            };
+            pipe.zadd(Key::index_key(&key.token), &key.key, score)
+                .ignore();
+            tokens.insert(&key.token);
+        }
+
+        for token in tokens {
+            pipe.cmd("EVAL")
+                .arg(EXPIRE_INDEX)
+                .arg(1)
+                .arg(Key::index_key(token))
+                .arg(self.ttl())
+                .ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut self.redis.clone()).await?;
+
+        Ok(keys.len())
+    }
+
+    /// Finds the token's keys of the layout before tokens were hash-tagged
+    /// with SCAN, renames them to where they're looked for now and indexes
+    /// them under the TTL of their longest-lived param.
+    ///
+    /// A param that's been written again since keeps the new value, its old
+    /// one is deleted. The old names hash anywhere, so on a cluster the
+    /// renames fail with CROSSSLOT, they're for the single node the old
+    /// layout ran on.
+    async fn scan_legacy(&self, token: &str) -> Result<Vec<String>> {
+        let pattern = format!("pcafe:{}:*", check_token(token)?);
+
+        let mut scanned = vec![];
+        let mut redis = self.redis.clone();
+        let mut iter = redis.scan_match::<_, String>(pattern).await?;
+
+        while let Some(redis_key) = iter.next_item().await {
+            scanned.push(redis_key);
+        }
+
+        let (found, renames, unparseable) = parse_scanned(&scanned);
+
+        if !unparseable.is_empty() {
+            self.unparseable
+                .fetch_add(unparseable.len(), Ordering::Relaxed);
+
+            if self.strict_scan {
+                eprintln!(
+                    "{} redis keys under pcafe:{} aren't params of a key, e.g. {:?}",
+                    unparseable.len(),
+                    token,
+                    &unparseable[..unparseable.len().min(SCAN_SAMPLE)]
+                );
+            }
+        }
+
+        if found.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.move_legacy(&renames).await?;
+        self.index_as_stored(&found).await?;
+
+        self.indexed(token).await
+    }
+
+    /// Renames redis keys of the old layout to their new names, see
+    /// `MOVE_LEGACY`.
+    async fn move_legacy(&self, renames: &[(&str, String)]) -> Result<()> {
+        let mut moves = redis::pipe();
+
+        for (old, new) in renames {
+            moves
+                .cmd("EVAL")
+                .arg(MOVE_LEGACY)
+                .arg(2)
+                .arg(*old)
+                .arg(new)
+                .ignore();
+        }
+
+        moves.query_async::<_, ()>(&mut self.redis.clone()).await?;
+
+        Ok(())
+    }
+
+    /// Indexes keys written by other than a send, moved from the old layout
+    /// or cloned, under the TTL of their longest-lived param, and expires
+    /// their tokens' indexes with them. A key without an expiry is indexed
+    /// for good, as `PERSIST_IF_DONE` does it, index and all.
+    async fn index_as_stored(&self, keys: &[Key]) -> Result<()> {
+        let mut ttls = redis::pipe();
+
+        for key in keys {
+            for param in KEY_PARAMS {
+                ttls.ttl(key.redis_key(param));
+            }
+        }
+
+        let ttls: Vec<i64> = ttls.query_async(&mut self.redis.clone()).await?;
+        let now = unix_now();
+        let mut index = redis::pipe();
+        // Each token's index lasts as long as its longest-lived key
+        let mut tokens = BTreeMap::new();
+
+        for (key, ttls) in keys.iter().zip(ttls.chunks(KEY_PARAMS.len())) {
+            let index_key = Key::index_key(&key.token);
+
+            // -1 is a key without an expiry, -2 one that's gone meanwhile
+            match ttls.iter().copied().max().unwrap_or(-2) {
+                -2 => continue,
+                -1 => index
+                    .zadd(&index_key, &key.key, "+inf")
+                    .ignore()
+                    .persist(&index_key)
+                    .ignore(),
+                ttl => index.zadd(&index_key, &key.key, now + ttl).ignore(),
+            };
+
+            let longest = tokens.entry(&key.token).or_insert(self.ttl() as i64);
+            *longest = (*longest).max(ttls.iter().copied().max().unwrap_or(0));
+        }
+
+        for (token, ttl) in tokens {
+            index
+                .cmd("EVAL")
+                .arg(EXPIRE_INDEX)
+                .arg(1)
+                .arg(Key::index_key(token))
+                .arg(ttl)
+                .ignore();
+        }
+
+        index.query_async::<_, ()>(&mut self.redis.clone()).await?;
+
+        Ok(())
+    }
+
+    /// Moves every key of the layout before tokens were hash-tagged to the
+    /// new names and indexes it, or only counts them when `dry_run`: what
+    /// `get_all_keys` does for one token on its first look, for all of them
+    /// at once. The keyspace is SCANned `GC_BATCH` keys at a time like in
+    /// `gc`, which deletes the old names as unparseable, so this goes first.
+    pub async fn migrate(&self, dry_run: bool) -> Result<MigrateReport> {
+        let mut report = MigrateReport::default();
+        let mut found = HashSet::new();
+        let mut cursor = 0u64;
+
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg("pcafe:*")
+                .arg("COUNT")
+                .arg(GC_BATCH)
+                .query_async(&mut self.redis.clone())
+                .await?;
+
+            report.scanned += batch.len();
+
+            let (keys, renames, _) = parse_scanned(&batch);
+            report.moved += renames.len();
+
+            if !dry_run && !renames.is_empty() {
+                self.move_legacy(&renames).await?;
+            }
+            found.extend(keys);
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        report.keys = found.len();
+
+        if !dry_run {
+            let found = Vec::from_iter(found);
+
+            for keys in found.chunks(GC_BATCH) {
+                self.index_as_stored(keys).await?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Resets the TTL of every param of `keys` to `ttl`, returns how many of
+    /// them still existed. `keys` are of one token: if it's frozen nothing
+    /// gets a TTL, the keys are only counted.
+    async fn touch(&self, keys: &[Key], ttl: usize) -> Result<usize> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let frozen = self.is_frozen(&keys[0].token).await?;
+        let mut pipe = redis::pipe();
+
+        for key in keys {
+            for param in KEY_PARAMS {
+                if frozen {
+                    pipe.exists(key.redis_key(param));
+                } else {
+                    pipe.expire(key.redis_key(param), ttl);
+                }
+            }
+        }
+
+        let refreshed: Vec<bool> = pipe.query_async(&mut self.redis.clone()).await?;
+
+        let existing: Vec<&Key> = keys
+            .iter()
+            .zip(refreshed.chunks(KEY_PARAMS.len()))
+            .filter(|(_, params)| params.iter().any(|r| *r))
+            .map(|(key, _)| key)
+            .collect();
+
+        if !frozen {
+            self.index(&existing, ttl).await?;
+        }
+
+        Ok(existing.len())
+    }
+
+    pub async fn is_frozen(&self, token: &str) -> Result<bool> {
+        Ok(self
+            .redis
+            .clone()
+            .exists(Key::frozen_key(check_token(token)?))
+            .await?)
+    }
+
+    /// Removes the TTL of every key of the token, of its index and its meta,
+    /// and flags the token so that later writes do the same, until
+    /// `unfreeze`. Returns how many keys were frozen.
+    pub async fn freeze(&self, token: &str) -> Result<usize> {
+        // Flagged first, so a write racing the listing persists itself
+        self.redis
+            .clone()
+            .set::<_, _, ()>(Key::frozen_key(check_token(token)?), "1")
+            .await?;
+
+        let keys = self.get_all_keys(token, "").await?;
+        let index = Key::index_key(token);
+        let mut pipe = redis::pipe();
+
+        for key in &keys {
+            for param in KEY_PARAMS {
+                pipe.persist(key.redis_key(param)).ignore();
+            }
+            pipe.zadd(&index, &key.key, "+inf").ignore();
+        }
+
+        pipe.persist(&index)
+            .ignore()
+            .persist(Key::meta_key(token))
+            .ignore()
+            .persist(Key::events_key(token))
+            .ignore()
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        Ok(keys.len())
+    }
+
+    /// Clears the flag of `freeze` and gives every key of the token the
+    /// default TTL again, like a touch. Returns how many keys were unfrozen.
+    pub async fn unfreeze(&self, token: &str) -> Result<usize> {
+        redis::pipe()
+            .del(Key::frozen_key(check_token(token)?))
+            .ignore()
+            .expire(Key::meta_key(token), self.ttl())
+            .ignore()
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+
+        self.touch_token(token).await
+    }
+
+    /// Copies every key of `from`, params and history, under `to` with the
+    /// same remaining TTL. Keys already under `to` are overwritten. Returns how
+    /// many keys were copied.
+    pub async fn clone_token(&self, from: &str, to: &str) -> Result<usize> {
+        check_token(to)?;
+
+        if from == to {
+            return Err(anyhow!("can't clone a token onto itself"));
+        }
+
+        let keys = Vec::from_iter(self.get_all_keys(from, "").await?);
+
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let mut dump = redis::pipe();
+
+        for key in &keys {
+            for param in KEY_PARAMS {
+                dump.cmd("DUMP")
+                    .arg(key.redis_key(param))
+                    .cmd("PTTL")
+                    .arg(key.redis_key(param));
+            }
+        }
+
+        let dumped: Vec<redis::Value> = dump.query_async(&mut self.redis.clone()).await?;
+        let cloned: Vec<Key> = keys
+            .iter()
+            .map(|k| Key {
+                token: to.to_owned(),
+                key: k.key.clone(),
+            })
+            .collect();
+
+        let mut restore = redis::pipe();
+        let targets = cloned
+            .iter()
+            .flat_map(|k| KEY_PARAMS.map(|p| k.redis_key(p)));
+
+        for (target, pair) in targets.zip(dumped.chunks(2)) {
+            let data: Option<Vec<u8>> = redis::from_redis_value(&pair[0])?;
+            let pttl: i64 = redis::from_redis_value(&pair[1])?;
+
+            if let Some(data) = data {
+                // RESTORE takes 0 for no expiry, which is what PTTL's -1 means
+                restore
+                    .cmd("RESTORE")
+                    .arg(target)
+                    .arg(pttl.max(0))
+                    .arg(data)
+                    .arg("REPLACE")
+                    .ignore();
+            }
+        }
+
+        restore
+            .query_async::<_, ()>(&mut self.redis.clone())
+            .await?;
+        self.index_as_stored(&cloned).await?;
+
+        Ok(cloned.len())
+    }
+
+    /// Makes `keys` the token's only keys: the other keys are deleted, the
+    /// missing ones created at 0 out of their max, if one is given, and the
+    /// ones already there left as they are. The deletes and creates go in
+    /// one MULTI, so a reader sees the token either before or after; a write
+    /// landing between listing the keys and that is kept.
+    pub async fn declare(&self, token: &str, keys: &[(Key, Option<i64>)]) -> Result<Declared> {
+        let existing = self.get_all_keys(check_token(token)?, "").await?;
+        let ttl = self.ttl();
+        let mut declared = Declared::default();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for key in existing
+            .iter()
+            .filter(|k| !keys.iter().any(|(d, _)| d == *k))
+        {
+            pipe.del(KEY_PARAMS.map(|p| key.redis_key(p)).to_vec())
+                .ignore()
+                .zrem(Key::index_key(&key.token), &key.key)
+                .ignore();
+            declared.removed += 1;
+        }
+
+        for (key, max) in keys {
+            if key.token != token {
+                return Err(anyhow!("{} isn't a key of {}", key.key, token));
+            }
+
+            if existing.contains(key) {
+                declared.kept += 1;
+                continue;
+            }
+
+            let update = match max {
+                Some(max) => Update::builder(key.clone()).current(0).max(*max),
+                None => Update::builder(key.clone()).current(0),
+            }
+            .build();
+
+            for c in update.as_cmds(ttl) {
+                pipe.add_command(c).ignore();
+            }
+            self.index_cmds(&mut pipe, key, self.ttl());
+            declared.added += 1;
+        }
+
+        pipe.query_async::<_, ()>(&mut self.redis.clone()).await?;
+
+        Ok(declared)
+    }
+
+    #[cfg(test)]
+    pub async fn delete_key(&self, key: &Key) -> Result<()> {
+        let mut pipe = redis::pipe();
+
+        pipe.atomic()
+            .del(KEY_PARAMS.map(|p| key.redis_key(p)).to_vec())
+            .ignore()
+            .zrem(Key::index_key(&key.token), &key.key)
+            .ignore();
+
+        Ok(pipe.query_async(&mut self.redis.clone()).await?)
+    }
+
+    /// Deletes every key of the token under `keyprefix`, all of their params
+    /// and their index entries, in one MULTI. Returns how many keys there
+    /// were, 0 for a token that has none.
+    pub async fn clear(&self, token: &str, keyprefix: &str) -> Result<usize> {
+        let keys = self.get_all_keys(check_token(token)?, keyprefix).await?;
+
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for key in &keys {
+            pipe.del(KEY_PARAMS.map(|p| key.redis_key(p)).to_vec())
+                .ignore()
+                .zrem(Key::index_key(&key.token), &key.key)
+                .ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut self.redis.clone()).await?;
+
+        Ok(keys.len())
+    }
+
+    /// Number of live keys of the token under `keyprefix`.
+    ///
+    /// Every write records the key in the token's index under the time it'll
+    /// expire, so for the whole token this is a ZCOUNT of the entries not
+    /// expired yet: cheap, read-only so a replica can answer it, and exact as long as keys only go away by expiring
+    /// or through `delete_key`. The index is ordered by expiry rather than by
+    /// name, so a prefix means going through all of the token's names.
+    pub async fn count_keys(&self, token: &str, keyprefix: &str) -> Result<usize> {
+        if !keyprefix.is_empty() {
+            return Ok(self.get_all_keys(token, keyprefix).await?.len());
+        }
+
+        let index = Key::index_key(check_token(token)?);
+
+        Ok(self
+            .redis
+            .clone()
+            .zcount(&index, format!("({}", unix_now()), "+inf")
+            .await?)
+    }
+
+    pub async fn touch_token(&self, token: &str) -> Result<usize> {
+        let keys = Vec::from_iter(self.get_all_keys(token, "").await?);
+
+        self.touch(&keys, self.ttl()).await
+    }
+
+    /// Touches the token's keys starting with `prefix`, for `ttl` seconds
+    /// rather than the default if given.
+    pub async fn touch_prefix(
+        &self,
+        token: &str,
+        prefix: &str,
+        ttl: Option<usize>,
+    ) -> Result<usize> {
+        let keys = Vec::from_iter(self.get_all_keys(token, check_key(prefix)?).await?);
+
+        self.touch(&keys, ttl.unwrap_or(self.ttl())).await
+    }
+
+    pub async fn touch_key(&self, key: &Key) -> Result<bool> {
+        Ok(self.touch(std::slice::from_ref(key), self.ttl()).await? > 0)
+    }
+
+    /// States of any keys, of any tokens, in a single MGET. The result is
+    /// aligned with `keys`.
+    pub async fn get_states(&self, keys: &[Key]) -> Result<Vec<Value>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let redis_keys: Vec<String> = keys
+            .iter()
+            .flat_map(|k| VALUE_PARAMS.map(|p| k.redis_key(p)))
+            .collect();
+
+        let raw: Vec<redis::Value> = redis::cmd("MGET")
+            .arg(redis_keys)
+            .query_async(&mut self.redis.clone())
+            .await?;
+
+        Ok(keys
+            .iter()
+            .zip(raw.chunks(VALUE_PARAMS.len()))
+            .map(|(key, v)| Value::from_redis(key, v))
+            .collect())
+    }
+
+    /// All keys of the token with their states, by `order` and then by key.
+    /// Keys without an order come last. The index can outlive a key's params,
+    /// say when they were deleted around the store, so keys with nothing
+    /// left are skipped.
+    pub async fn get_all_states(&self, token: &str, keyprefix: &str) -> Result<Vec<(Key, Value)>> {
+        let keys = Vec::from_iter(self.get_all_keys(token, keyprefix).await?);
+        let states = self.get_states(&keys).await?;
+
+        let mut all: Vec<(Key, Value)> = keys
+            .into_iter()
+            .zip(states)
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        sort_by_order(&mut all);
+
+        Ok(all)
+    }
+}
+
+/// By `order` and then by key, keys without an order last.
+fn sort_by_order(all: &mut [(Key, Value)]) {
+    all.sort_by(|(ka, va), (kb, vb)| {
+        (va.order.is_none(), va.order, ka).cmp(&(vb.order.is_none(), vb.order, kb))
+    });
+}
+
+/// The part of a store that writing and reading single keys needs, so the
+/// handlers built on it can be tried against a `MemoryStore`.
+pub trait ProgressBackend: Clone + Send + Sync + 'static {
+    fn update(&self, update: &Update) -> impl Future<Output = Result<()>> + Send;
+
+    /// The updates of a send, one by one unless the store has a better way.
+    fn update_many(&self, updates: &[Update]) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            for update in updates {
+                self.update(update).await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// See `Store::update_if_newer`.
+    fn update_if_newer(
+        &self,
+        update: &Update,
+        timestamp: i64,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// See `Store::init_if_absent`.
+    fn init_if_absent(&self, update: &Update) -> impl Future<Output = Result<bool>> + Send;
+
+    /// `update_if_newer` for each of a send's updates, one by one unless the
+    /// store has a better way.
+    fn update_many_if_newer(
+        &self,
+        updates: &[Update],
+        timestamp: i64,
+    ) -> impl Future<Output = Result<Vec<bool>>> + Send {
+        async move {
+            let mut applied = vec![];
+
+            for update in updates {
+                applied.push(self.update_if_newer(update, timestamp).await?);
+            }
+
+            Ok(applied)
+        }
+    }
+
+    /// `init_if_absent` for each of a send's updates, one by one unless the
+    /// store has a better way.
+    fn init_many_if_absent(
+        &self,
+        updates: &[Update],
+    ) -> impl Future<Output = Result<Vec<bool>>> + Send {
+        async move {
+            let mut created = vec![];
+
+            for update in updates {
+                created.push(self.init_if_absent(update).await?);
+            }
+
+            Ok(created)
+        }
+    }
+
+    fn get_state(&self, key: &Key) -> impl Future<Output = Result<Value>> + Send;
+
+    /// The states of `keys`, in their order, one by one unless the store has
+    /// a better way.
+    fn get_states(&self, keys: &[Key]) -> impl Future<Output = Result<Vec<Value>>> + Send {
+        async move {
+            let mut states = vec![];
+
+            for key in keys {
+                states.push(self.get_state(key).await?);
+            }
+
+            Ok(states)
+        }
+    }
+
+    fn get_history(&self, key: &Key) -> impl Future<Output = Result<Vec<HistoryPoint>>> + Send;
+
+    /// `get_history` of each of `keys`, one by one unless the store has a
+    /// better way.
+    fn get_histories(
+        &self,
+        keys: &[Key],
+    ) -> impl Future<Output = Result<Vec<Vec<HistoryPoint>>>> + Send {
+        async move {
+            let mut histories = vec![];
+
+            for key in keys {
+                histories.push(self.get_history(key).await?);
+            }
+
+            Ok(histories)
+        }
+    }
+
+    fn get_meta(&self, token: &str) -> impl Future<Output = Result<TokenMeta>> + Send;
+
+    fn claim_send(
+        &self,
+        token: &str,
+        idem: &str,
+        fingerprint: &str,
+    ) -> impl Future<Output = Result<Claim>> + Send;
+
+    fn finish_send(
+        &self,
+        token: &str,
+        idem: &str,
+        fingerprint: &str,
+        body: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn release_send(&self, token: &str, idem: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn mark_notified(&self, token: &str) -> impl Future<Output = Result<bool>> + Send;
+
+    fn clear_notified(&self, token: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn remember_view(&self, view: &str, token: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn view_of(&self, view: &str) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    fn count_view(&self, token: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_views(&self, token: &str) -> impl Future<Output = Result<i64>> + Send;
+
+    fn save_key_set(
+        &self,
+        token: &str,
+        hash: &str,
+        names: &[&str],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_key_set(
+        &self,
+        token: &str,
+        hash: &str,
+    ) -> impl Future<Output = Result<Option<Vec<String>>>> + Send;
+
+    fn get_all_keys(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> impl Future<Output = Result<HashSet<Key>>> + Send;
+
+    /// See `Store::count_keys`.
+    fn count_keys(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> impl Future<Output = Result<usize>> + Send {
+        async move { Ok(self.get_all_keys(token, keyprefix).await?.len()) }
+    }
+
+    /// Whether the store answers at all, see /health.
+    fn ping(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// All keys of the token with their states, in the order of
+    /// `Store::get_all_states`, reading them one by one unless the store
+    /// has a better way.
+    fn get_all_states(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> impl Future<Output = Result<Vec<(Key, Value)>>> + Send {
+        async move {
+            let mut all = vec![];
+
+            for key in self.get_all_keys(token, keyprefix).await? {
+                let value = self.get_state(&key).await?;
+
+                if !value.is_empty() {
+                    all.push((key, value));
+                }
+            }
+            sort_by_order(&mut all);
+
+            Ok(all)
+        }
+    }
+}
+
+impl<C: Connection> ProgressBackend for Store<C> {
+    fn update(&self, update: &Update) -> impl Future<Output = Result<()>> + Send {
+        Store::update(self, update)
+    }
+
+    fn update_many(&self, updates: &[Update]) -> impl Future<Output = Result<()>> + Send {
+        Store::update_many(self, updates)
+    }
+
+    fn update_if_newer(
+        &self,
+        update: &Update,
+        timestamp: i64,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        Store::update_if_newer(self, update, timestamp)
+    }
+
+    fn init_if_absent(&self, update: &Update) -> impl Future<Output = Result<bool>> + Send {
+        Store::init_if_absent(self, update)
+    }
+
+    fn update_many_if_newer(
+        &self,
+        updates: &[Update],
+        timestamp: i64,
+    ) -> impl Future<Output = Result<Vec<bool>>> + Send {
+        Store::update_many_if_newer(self, updates, timestamp)
+    }
+
+    fn init_many_if_absent(
+        &self,
+        updates: &[Update],
+    ) -> impl Future<Output = Result<Vec<bool>>> + Send {
+        Store::init_many_if_absent(self, updates)
+    }
+
+    fn get_state(&self, key: &Key) -> impl Future<Output = Result<Value>> + Send {
+        Store::get_state(self, key)
+    }
+
+    fn get_states(&self, keys: &[Key]) -> impl Future<Output = Result<Vec<Value>>> + Send {
+        Store::get_states(self, keys)
+    }
+
+    fn get_history(&self, key: &Key) -> impl Future<Output = Result<Vec<HistoryPoint>>> + Send {
+        Store::get_history(self, key)
+    }
+
+    fn get_histories(
+        &self,
+        keys: &[Key],
+    ) -> impl Future<Output = Result<Vec<Vec<HistoryPoint>>>> + Send {
+        Store::get_histories(self, keys)
+    }
+
+    fn get_meta(&self, token: &str) -> impl Future<Output = Result<TokenMeta>> + Send {
+        Store::get_meta(self, token)
+    }
+
+    fn claim_send(
+        &self,
+        token: &str,
+        idem: &str,
+        fingerprint: &str,
+    ) -> impl Future<Output = Result<Claim>> + Send {
+        Store::claim_send(self, token, idem, fingerprint)
+    }
+
+    fn finish_send(
+        &self,
+        token: &str,
+        idem: &str,
+        fingerprint: &str,
+        body: &str,
+    ) -> impl Future<Output = Result<()>> + Send {
+        Store::finish_send(self, token, idem, fingerprint, body)
+    }
+
+    fn release_send(&self, token: &str, idem: &str) -> impl Future<Output = Result<()>> + Send {
+        Store::release_send(self, token, idem)
+    }
+
+    fn mark_notified(&self, token: &str) -> impl Future<Output = Result<bool>> + Send {
+        Store::mark_notified(self, token)
+    }
+
+    fn clear_notified(&self, token: &str) -> impl Future<Output = Result<()>> + Send {
+        Store::clear_notified(self, token)
+    }
+
+    fn remember_view(&self, view: &str, token: &str) -> impl Future<Output = Result<()>> + Send {
+        Store::remember_view(self, view, token)
+    }
+
+    fn view_of(&self, view: &str) -> impl Future<Output = Result<Option<String>>> + Send {
+        Store::view_of(self, view)
+    }
+
+    fn count_view(&self, token: &str) -> impl Future<Output = Result<()>> + Send {
+        Store::count_view(self, token)
+    }
+
+    fn get_views(&self, token: &str) -> impl Future<Output = Result<i64>> + Send {
+        Store::get_views(self, token)
+    }
+
+    fn save_key_set(
+        &self,
+        token: &str,
+        hash: &str,
+        names: &[&str],
+    ) -> impl Future<Output = Result<()>> + Send {
+        Store::save_key_set(self, token, hash, names)
+    }
+
+    fn get_key_set(
+        &self,
+        token: &str,
+        hash: &str,
+    ) -> impl Future<Output = Result<Option<Vec<String>>>> + Send {
+        Store::get_key_set(self, token, hash)
+    }
+
+    fn get_all_keys(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> impl Future<Output = Result<HashSet<Key>>> + Send {
+        Store::get_all_keys(self, token, keyprefix)
+    }
+
+    fn count_keys(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> impl Future<Output = Result<usize>> + Send {
+        Store::count_keys(self, token, keyprefix)
+    }
+
+    fn ping(&self) -> impl Future<Output = Result<()>> + Send {
+        Store::ping(self)
+    }
+
+    fn get_all_states(
+        &self,
+        token: &str,
+        keyprefix: &str,
+    ) -> impl Future<Output = Result<Vec<(Key, Value)>>> + Send {
+        Store::get_all_states(self, token, keyprefix)
+    }
+}
+
+/// A `ProgressBackend` in memory, for tests without a Redis. It keeps the
+/// params by their Redis keys and reads them back with `Value::from_redis`,
+/// as `Store` does, and the rest of what's under `pcafe:` by its Redis key in
+/// `others`. Nothing expires and there's no history or meta.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    params: Arc<std::sync::Mutex<std::collections::HashMap<String, String>>>,
+    others: Arc<std::sync::Mutex<std::collections::HashMap<String, String>>>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    fn other(&self, key: &str) -> Option<String> {
+        self.others.lock().unwrap().get(key).cloned()
+    }
+
+    fn set_other(&self, key: String, val: String) {
+        self.others.lock().unwrap().insert(key, val);
+    }
+
+    fn del_other(&self, key: &str) -> Option<String> {
+        self.others.lock().unwrap().remove(key)
+    }
+}
+
+#[cfg(test)]
+impl ProgressBackend for MemoryStore {
+    async fn update(&self, update: &Update) -> Result<()> {
+        let mut params = self.params.lock().unwrap();
+        let key = &update.key;
+
+        for (param, val) in update.changes() {
+            match val {
+                Some(val) => params.insert(key.redis_key(param), val),
+                None => params.remove(&key.redis_key(param)),
+            };
+        }
+
+        let number = |params: &std::collections::HashMap<String, String>, param| {
+            params
+                .get(&key.redis_key(param))
+                .and_then(|v| v.parse::<i64>().ok())
+        };
+
+        // As `INCREMENT` and `SET_MAX` do
+        if let Some(delta) = update.increment {
+            let current = number(&params, "current").unwrap_or(0) + delta;
+            params.insert(key.redis_key("current"), current.to_string());
+        }
+
+        if let Some(max) = update.set_max {
+            params.insert(key.redis_key("max"), max.to_string());
+
+            if let Some(current) = number(&params, "current").filter(|c| *c > max) {
+                params.insert(key.redis_key("current"), current.min(max).to_string());
+            }
+        }
+
+        let now = unix_now().to_string();
+        params
+            .entry(key.redis_key("created_at"))
+            .or_insert_with(|| now.clone());
+        params.insert(key.redis_key("updated_at"), now);
+
+        Ok(())
+    }
+
+    async fn get_state(&self, key: &Key) -> Result<Value> {
+        let params = self.params.lock().unwrap();
+        let raw = VALUE_PARAMS.map(|param| match params.get(&key.redis_key(param)) {
+            Some(v) => redis::Value::Data(v.as_bytes().to_vec()),
+            None => redis::Value::Nil,
+        });
+
+        Ok(Value::from_redis(key, &raw))
+    }
+
+    async fn get_all_keys(&self, token: &str, keyprefix: &str) -> Result<HashSet<Key>> {
+        check_token(token)?;
+
+        Ok(self
+            .params
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|k| Key::from_redis_key(k).ok())
+            .filter(|k| k.token == token && k.key.starts_with(keyprefix))
+            .collect())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // As `SET_IF_NEWER`
+    async fn update_if_newer(&self, update: &Update, timestamp: i64) -> Result<bool> {
+        if update.increment.is_some() || update.set_max.is_some() {
+            return Err(anyhow!("increments and setmax can't be combined with ts"));
+        }
+
+        let ts = update.key.redis_key("ts");
+        let stored = self.params.lock().unwrap().get(&ts).cloned();
+
+        if stored.and_then(|s| s.parse::<i64>().ok()) > Some(timestamp) {
+            return Ok(false);
+        }
+
+        self.params
+            .lock()
+            .unwrap()
+            .insert(ts, timestamp.to_string());
+        self.update(update).await?;
+
+        Ok(true)
+    }
+
+    // As `INIT_IF_ABSENT`
+    async fn init_if_absent(&self, update: &Update) -> Result<bool> {
+        if update.increment.is_some() || update.set_max.is_some() {
+            return Err(anyhow!("increments and setmax can't be combined with init"));
+        }
+
+        if !self.get_state(&update.key).await?.is_empty() {
+            return Ok(false);
+        }
+        self.update(update).await?;
+
+        Ok(true)
+    }
+
+    async fn get_history(&self, _: &Key) -> Result<Vec<HistoryPoint>> {
+        Ok(vec![])
+    }
+
+    async fn get_meta(&self, token: &str) -> Result<TokenMeta> {
+        check_token(token)?;
+
+        Ok(TokenMeta::default())
+    }
+
+    async fn claim_send(&self, token: &str, idem: &str, fingerprint: &str) -> Result<Claim> {
+        let key = Key::idempotency_key(check_token(token)?, check_idempotency_key(idem)?);
+
+        Ok(match self.other(&key) {
+            Some(record) => Claim::of_record(&record, fingerprint),
+            None => {
+                self.set_other(key, format!("{}\n", fingerprint));
+                Claim::Claimed
+            }
+        })
+    }
+
+    async fn finish_send(
+        &self,
+        token: &str,
+        idem: &str,
+        fingerprint: &str,
+        body: &str,
+    ) -> Result<()> {
+        let key = Key::idempotency_key(check_token(token)?, check_idempotency_key(idem)?);
+
+        if self.other(&key).is_some() {
+            self.set_other(key, format!("{}\n{}", fingerprint, body));
+        }
+
+        Ok(())
+    }
+
+    async fn release_send(&self, token: &str, idem: &str) -> Result<()> {
+        self.del_other(&Key::idempotency_key(
+            check_token(token)?,
+            check_idempotency_key(idem)?,
+        ));
+
+        Ok(())
+    }
+
+    async fn mark_notified(&self, token: &str) -> Result<bool> {
+        let key = Key::notified_key(check_token(token)?);
+        let unset = self.other(&key).is_none();
+        self.set_other(key, "1".to_owned());
+
+        Ok(unset)
+    }
+
+    async fn clear_notified(&self, token: &str) -> Result<()> {
+        self.del_other(&Key::notified_key(check_token(token)?));
+
+        Ok(())
+    }
+
+    async fn remember_view(&self, view: &str, token: &str) -> Result<()> {
+        self.set_other(
+            Key::view_key(check_token(view)?),
+            check_token(token)?.to_owned(),
+        );
+
+        Ok(())
+    }
+
+    async fn view_of(&self, view: &str) -> Result<Option<String>> {
+        Ok(self.other(&Key::view_key(check_token(view)?)))
+    }
+
+    async fn count_view(&self, token: &str) -> Result<()> {
+        let views = self.get_views(token).await? + 1;
+        self.set_other(Key::views_key(token), views.to_string());
+
+        Ok(())
+    }
+
+    async fn get_views(&self, token: &str) -> Result<i64> {
+        Ok(self
+            .other(&Key::views_key(check_token(token)?))
+            .map_or(Ok(0), |v| v.parse())?)
+    }
+
+    async fn save_key_set(&self, token: &str, hash: &str, names: &[&str]) -> Result<()> {
+        self.set_other(Key::key_set_key(check_token(token)?, hash), names.join(","));
+
+        Ok(())
+    }
+
+    async fn get_key_set(&self, token: &str, hash: &str) -> Result<Option<Vec<String>>> {
+        Ok(self
+            .other(&Key::key_set_key(check_token(token)?, hash))
+            .map(|n| {
+                n.split(',')
+                    .filter(|n| !n.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use anyhow::{anyhow, Result};
+    use redis::aio::ConnectionManager;
+    use redis::AsyncCommands;
+
+    use crate::store::{
+        check_idempotency_key, check_key, check_token, downsample, format_percent, gc_parse,
+        group_rollups, limit_state, nested_rollups, overall_percent, parse_precision,
+        parse_scanned, reaches_percent, round_percent, set_done_percent, summarize, Claim,
+        CompletionEvent, Declared, Eta, ExportedKey, Garbage, GroupRollup, HistoryPoint, Key,
+        Metric, Segments, StatusSummary, Store, TokenMeta, Update, Value, DONE_PERCENT, KEY_PARAMS,
+        VALUE_PARAMS,
+    };
+
+    fn parse(val: &str) -> Result<Update> {
+        Update::from_query("tok", ("build".to_owned(), val.to_owned()))
+    }
+
+    /// A connection that counts its round trips, answering 0 to every command
+    /// of a pipeline and an empty list to one on its own.
+    #[derive(Clone, Default)]
+    struct RoundTrips(Arc<AtomicUsize>);
+
+    impl redis::aio::ConnectionLike for RoundTrips {
+        fn req_packed_command<'a>(
+            &'a mut self,
+            _: &'a redis::Cmd,
+        ) -> redis::RedisFuture<'a, redis::Value> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            Box::pin(async { Ok(redis::Value::Bulk(vec![])) })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a redis::Pipeline,
+            offset: usize,
+            count: usize,
+        ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            // A transaction wants the EXEC's reply alone, after a QUEUED for
+            // each of its commands. Nil reads as an empty list or no value.
+            let replies = match offset {
+                0 => vec![redis::Value::Nil; count],
+                queued => vec![redis::Value::Bulk(vec![redis::Value::Int(0); queued - 1])],
+            };
+
+            Box::pin(async { Ok(replies) })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn hundred_keys(token: &str) -> Result<Vec<Update>> {
+        (0..100)
+            .map(|i| {
+                let val = if i % 2 == 0 { "+1/100" } else { "50/100" };
+
+                Update::from_query(token, (format!("key{}", i), val.to_owned()))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_batch_is_one_round_trip() -> Result<()> {
+        let trips = RoundTrips::default();
+        let store = Store::new(trips.clone());
+
+        store.update_many(&hundred_keys("tok")?).await?;
+        assert_eq!(trips.0.load(Ordering::Relaxed), 1);
+
+        // Downsampling reads the histories back, one key at a time
+        let downsampled = store.with_downsampled_history(true);
+        downsampled.update_many(&hundred_keys("tok")?).await?;
+        assert!(trips.0.load(Ordering::Relaxed) > 100);
+
+        // As are sends with a ts or init=1
+        let trips = RoundTrips::default();
+        let store = Store::new(trips.clone());
+        let plain = (0..100)
+            .map(|i| Update::from_query("tok", (format!("key{}", i), "50/100".to_owned())))
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(store.update_many_if_newer(&plain, 100).await?.len(), 100);
+        assert_eq!(store.init_many_if_absent(&plain).await?.len(), 100);
+        assert_eq!(trips.0.load(Ordering::Relaxed), 2);
+
+        // Nothing goes out for a send that can't be written
+        let mut mixed = plain;
+        mixed.push(parse("+1")?);
+        assert!(store.update_many_if_newer(&mixed, 100).await.is_err());
+        assert!(store.init_many_if_absent(&mixed).await.is_err());
+        assert_eq!(trips.0.load(Ordering::Relaxed), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn histories_are_one_round_trip() -> Result<()> {
+        let trips = RoundTrips::default();
+        let store = Store::new(trips.clone());
+        let keys = hundred_keys("tok")?
+            .iter()
+            .map(|u| u.key().clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(store.get_histories(&keys).await?.len(), 100);
+        assert_eq!(trips.0.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    }
+
+    /// Run with `--ignored --nocapture` for the time a 100-key send takes.
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn bench_hundred_key_send() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+        let updates = hundred_keys("benchtoken")?;
+        let runs = 50;
+
+        store.update_many(&updates).await?;
+        let started = Instant::now();
+        for _ in 0..runs {
+            store.update_many(&updates).await?;
+        }
+        println!("a 100-key send takes {:?}", started.elapsed() / runs);
+
+        for key in store.get_all_keys("benchtoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn state_only_leaves_numbers_untouched() -> Result<()> {
+        for val in ["compiling!", "compiling!/"] {
+            let u = parse(val)?;
+            assert_eq!(u.state, Some(Some("compiling".to_owned())));
+            assert_eq!(u.current, None);
+            assert_eq!(u.max, None);
+        }
+
+        let u = parse("compiling!5/")?;
+        assert_eq!((u.current, u.max), (Some(Some(5)), None));
+
+        let u = parse("compiling!/100")?;
+        assert_eq!((u.current, u.max), (None, Some(Some(100))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_grammar() -> Result<()> {
+        let state = |s: &str| Some(Some(s.to_owned()));
+        let n = |n: i64| Some(Some(n));
+        let no_state = Some(None);
+        let null = Some(None);
+
+        // value, then state, current, max and increment as parsed. A value
+        // without a `!` clears the state
+        let cases = [
+            ("5/100", no_state.clone(), n(5), n(100), None),
+            ("5", no_state.clone(), n(5), None, None),
+            ("/100", no_state.clone(), None, n(100), None),
+            ("5/", no_state.clone(), n(5), None, None),
+            ("null/100", no_state.clone(), null, n(100), None),
+            ("5/null", no_state.clone(), n(5), null, None),
+            ("NULL/Null", no_state.clone(), null, null, None),
+            ("linking!5/100", state("linking"), n(5), n(100), None),
+            ("linking!", state("linking"), None, None, None),
+            ("linking!/100", state("linking"), None, n(100), None),
+            ("linking!5", state("linking"), n(5), None, None),
+            ("linking!5/", state("linking"), n(5), None, None),
+            ("linking!null", state("linking"), null, None, None),
+            ("+3/100", no_state.clone(), None, n(100), Some(3)),
+            ("linking!+-2", state("linking"), None, None, Some(-2)),
+            ("\t5 / 100 ", no_state.clone(), n(5), n(100), None),
+            ("", no_state.clone(), None, None, None),
+            (
+                "Downloading file 3 of 10!3/10",
+                state("Downloading file 3 of 10"),
+                n(3),
+                n(10),
+                None,
+            ),
+            (
+                "Done! 3 of 10!3/10",
+                state("Done! 3 of 10"),
+                n(3),
+                n(10),
+                None,
+            ),
+            ("Done!!", state("Done!"), None, None, None),
+            ("<b>!5", state("<b>"), n(5), None, None),
+            ("¡listo!5", state("¡listo"), n(5), None, None),
+        ];
+
+        for (val, state, current, max, increment) in cases {
+            let u = parse(val)?;
+            assert_eq!(
+                (u.state, u.current, u.max, u.increment),
+                (state, current, max, increment),
+                "{:?}",
+                val
+            );
+            assert_eq!((u.set_max, u.state_truncated), (None, false), "{:?}", val);
+        }
+
+        let malformed = [
+            "five",
+            "5/ten",
+            "5/100/3",
+            "1.5",
+            "5/1e3",
+            "+",
+            "+x",
+            "+1+1",
+            "linking!five",
+            "bad\nstate!5",
+        ];
+        for val in malformed {
+            assert!(parse(val).is_err(), "{:?}", val);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_number_forms() -> Result<()> {
+        let u = parse("/100")?;
+        assert_eq!((u.current, u.max), (None, Some(Some(100))));
+
+        let u = parse("5/")?;
+        assert_eq!((u.current, u.max), (Some(Some(5)), None));
+
+        let u = parse("/null")?;
+        assert_eq!((u.current, u.max), (None, Some(None)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn downsample_keeps_the_whole_run() {
+        let points: Vec<HistoryPoint> = (0..8)
+            .map(|i| HistoryPoint {
+                t: i,
+                current: i * 10,
+            })
+            .collect();
+
+        let compacted = downsample(&points);
+
+        assert_eq!(compacted.len(), 6);
+        assert_eq!(compacted[0], HistoryPoint { t: 0, current: 5 });
+        assert_eq!(compacted[1], HistoryPoint { t: 2, current: 25 });
+        assert_eq!(&compacted[2..], &points[4..]);
+    }
+
+    fn cmd_names(u: &Update) -> Vec<(String, String)> {
+        u.as_cmds(60)
+            .map(|c| {
+                let mut args = c.args_iter().filter_map(|a| match a {
+                    redis::Arg::Simple(a) => Some(String::from_utf8_lossy(a).into_owned()),
+                    redis::Arg::Cursor => None,
+                });
+
+                (
+                    args.next().unwrap_or_default(),
+                    args.next().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn set_max_is_one_script() -> Result<()> {
+        let u = Update::from_query("tok", ("build.setmax".to_owned(), "500".to_owned()))?;
+        assert_eq!((u.set_max, u.max, u.current), (Some(500), None, None));
+
+        // max and current are left to the script, nothing else is written
+        let cmds = cmd_names(&u);
+        assert!(!cmds.iter().any(|(_, k)| *k == u.key().redis_key("max")));
+        assert!(!cmds.iter().any(|(c, _)| c == "DEL"));
+
+        for bad in ["0", "-5", "null", "", "many"] {
+            assert!(
+                Update::from_query("tok", ("build.setmax".to_owned(), bad.to_owned())).is_err(),
+                "{}",
+                bad
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_a_single_field() -> Result<()> {
+        for param in ["state", "current", "max", "color", "order", "error", "note"] {
+            let u = Update::from_query("tok", (format!("build.{}", param), "null".to_owned()))?;
+            let cmds = cmd_names(&u);
+
+            let dels = cmds.iter().filter(|(c, _)| c == "DEL").collect::<Vec<_>>();
+            assert_eq!(dels, [&("DEL".to_owned(), u.key().redis_key(param))]);
+
+            // Besides the DEL only created_at (NX) and updated_at are written,
+            // everything else just has its TTL refreshed
+            let writes = cmds
+                .iter()
+                .filter(|(c, _)| c != "DEL" && c != "EXPIRE")
+                .map(|(_, k)| k.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                writes,
+                [
+                    u.key().redis_key("created_at"),
+                    u.key().redis_key("updated_at")
+                ],
+                "{}",
+                param
+            );
+            assert!(!cmds.contains(&("EXPIRE".to_owned(), u.key().redis_key(param))));
+        }
+
+        let u = Update::from_query("tok", ("build.max".to_owned(), "100".to_owned()))?;
+        assert_eq!((u.state, u.current, u.max), (None, None, Some(Some(100))));
+
+        let u = Update::from_query("tok", ("build.state".to_owned(), "linking".to_owned()))?;
+        assert_eq!(u.state, Some(Some("linking".to_owned())));
+
+        let u = Update::from_query("tok", ("build.state".to_owned(), "3 of 10!".to_owned()))?;
+        assert_eq!(u.state, Some(Some("3 of 10!".to_owned())));
+        assert!(
+            Update::from_query("tok", ("build.state".to_owned(), "a\u{7}".to_owned())).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn increments() -> Result<()> {
+        let u = parse("+1/100")?;
+        assert_eq!(
+            (u.increment, u.current, u.max),
+            (Some(1), None, Some(Some(100)))
+        );
+        // color, order, error, note, persist, weight, tags, segments, duration,
+        // indeterminate, icon, reverse, type and ts are only refreshed
+        assert_eq!(u.as_cmds(60).count(), 4 + 14);
+        assert!(cmd_names(&u).contains(&("EXPIRE".to_owned(), u.key.redis_key("ts"))));
+
+        let u = parse("uploading!+-3")?;
+        assert_eq!((u.increment, u.current), (Some(-3), None));
+        assert_eq!(u.state, Some(Some("uploading".to_owned())));
+
+        // An unescaped + of a query string, decoded to a space
+        let u = parse(" 1/200")?;
+        assert_eq!(
+            (u.increment, u.current, u.max),
+            (Some(1), None, Some(Some(200)))
+        );
+        assert_eq!(parse("uploading! 2")?.increment, Some(2));
+        assert_eq!(parse("\n5")?.current, Some(Some(5)));
+
+        assert_eq!(parse("5")?.increment, None);
+        assert!(parse("+x").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_and_magnitude() {
+        let err = |v: &str| parse(v).unwrap_err().to_string();
+
+        assert!(err("/99999999999999999999").contains("doesn't fit"));
+        assert!(err("-99999999999999999999").contains("doesn't fit"));
+        assert!(err("/12x").contains("isn't a whole number"));
+        assert!(err("/2000000000000000").contains("out of range"));
+        assert!(err("+-2000000000000000").contains("out of range"));
+
+        assert!(parse("1000000000000000").is_ok());
+        assert!(parse("-1000000000000000").is_ok());
+    }
+
+    #[test]
+    fn state_length() -> Result<()> {
+        let state = |len| "s".repeat(len) + "!5";
+
+        assert_eq!(parse(&state(512))?.state, Some(Some("s".repeat(512))));
+        assert!(parse(&state(513))
+            .unwrap_err()
+            .to_string()
+            .contains("at most 512"));
+
+        assert_eq!(limit_state("abcd", 4, false)?, ("abcd", false));
+        assert!(limit_state("abcde", 4, false).is_err());
+        assert_eq!(limit_state("abcde", 4, true)?, ("abcd", true));
+        assert_eq!(limit_state("abcé", 4, true)?, ("abc", true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn builder_matches_parsing() -> Result<()> {
+        let key = || Key::try_from(("tok", "build"));
+
+        assert_eq!(
+            Update::builder(key()?)
+                .state("compiling")
+                .current(5)
+                .max(10)
+                .build(),
+            parse("compiling!5/10")?
+        );
+        assert_eq!(
+            Update::builder(key()?)
+                .clear_state()
+                .clear_current()
+                .build(),
+            parse("null")?
+        );
+        assert_eq!(
+            Update::builder(key()?).clear_state().current(1).build(),
+            Update::new(key()?, None, Some(Some(1)), None)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() -> Result<()> {
+        let u = parse("\t5 ")?;
+        assert_eq!((u.current, u.max), (Some(Some(5)), None));
+
+        let u = parse("5\n/100")?;
+        assert_eq!((u.current, u.max), (Some(Some(5)), Some(Some(100))));
+
+        let u = parse("compiling!\n5 / 100\n")?;
+        assert_eq!(u.state, Some(Some("compiling".to_owned())));
+        assert_eq!((u.current, u.max), (Some(Some(5)), Some(Some(100))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn color_attribute() -> Result<()> {
+        let u = Update::from_query("tok", ("build.color".to_owned(), "#ff8800".to_owned()))?;
+        assert_eq!(u.key.key, "build");
+        assert_eq!(u.color, Some(Some("#ff8800".to_owned())));
+        assert_eq!((u.state, u.current, u.max), (None, None, None));
+
+        let u = Update::from_query("tok", ("build.color".to_owned(), "null".to_owned()))?;
+        assert_eq!(u.color, Some(None));
+
+        for bad in [
+            "#ff88",
+            "red;background:url(x)",
+            "#ff8800'",
+            "expression(1)",
+        ] {
+            assert!(Update::from_query("tok", ("build.color".to_owned(), bad.to_owned())).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn order_attribute() -> Result<()> {
+        let u = Update::from_query("tok", ("upload.order".to_owned(), "3".to_owned()))?;
+        assert_eq!(u.key.key, "upload");
+        assert_eq!(u.order, Some(Some(3)));
+        assert_eq!((u.state, u.current, u.max), (None, None, None));
+
+        let u = Update::from_query("tok", ("v1.2".to_owned(), "5".to_owned()))?;
+        assert_eq!(u.key.key, "v1.2");
+        assert_eq!(u.current, Some(Some(5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_token_vs_empty_prefix() -> Result<()> {
+        assert!(Key::try_from(("", "key")).is_err());
+        assert!(Update::from_query("", ("key".to_owned(), "5".to_owned())).is_err());
+
+        assert!(Key::from_redis_key("pcafe:{}:key:state").is_err());
+        assert!(Key::from_redis_key("pcafe:{tok}").is_err());
+        assert_eq!(Key::from_redis_key("pcafe:{tok}:a:b:state")?.key, "a:b");
+        assert!(Key::from_redis_key("pcafe:{tok}:state").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scanned_keys() -> Result<()> {
+        let scanned = [
+            "pcafe:tok:a:state",
+            "pcafe:tok:a:current",
+            "pcafe:tok:b:c:max",
+            "pcafe:tok:lonely",
+            "pcafe:tok:bad key:state",
+        ]
+        .map(str::to_owned);
+
+        let (found, renames, unparseable) = parse_scanned(&scanned);
+        assert_eq!(
+            found.iter().map(|k| k.key.as_str()).collect::<Vec<_>>(),
+            ["a", "b:c"]
+        );
+        assert_eq!(
+            renames,
+            [
+                ("pcafe:tok:a:state", "pcafe:{tok}:a:state".to_owned()),
+                ("pcafe:tok:a:current", "pcafe:{tok}:a:current".to_owned()),
+                ("pcafe:tok:b:c:max", "pcafe:{tok}:b:c:max".to_owned()),
+            ]
+        );
+        assert_eq!(unparseable, ["pcafe:tok:lonely", "pcafe:tok:bad key:state"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keys_vs_tokens() -> Result<()> {
+        assert_eq!(check_key("deploy:eu-west:node.1")?, "deploy:eu-west:node.1");
+        assert!(check_token("deploy:eu").is_err());
+        assert_eq!(check_token("my_token-1.2")?, "my_token-1.2");
+
+        for glob in ["a*", "a?", "a[0]", "a\\b", "a b", "a/b", ""] {
+            assert!(check_key(glob).is_err(), "{:?}", glob);
+            assert!(check_token(glob).is_err(), "{:?}", glob);
+        }
+
+        assert!(Key::try_from(("tok", "build:linux")).is_ok());
+        assert!(Key::try_from(("tok:x", "build")).is_err());
+        assert!(Key::try_from(("tok", "batch")).is_err());
+        assert!(Key::try_from(("tok", "batch:linux")).is_ok());
+        assert!(Key::try_from(("tok", "build*")).is_err());
+
+        let u = Update::from_query("tok", ("ci:build.max".to_owned(), "10".to_owned()))?;
+        assert_eq!(u.key.key, "ci:build");
+        assert!(Update::from_query("tok", ("ci*".to_owned(), "10".to_owned())).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn token_is_a_hash_tag() -> Result<()> {
+        let key = Key::try_from(("tok", "a:b"))?;
+
+        assert_eq!(key.redis_key("state"), "pcafe:{tok}:a:b:state");
+        assert_eq!(Key::from_redis_key(&key.redis_key("state"))?, key);
+        assert!(Key::from_redis_key("pcafe:tok:a:b:state").is_err());
+
+        assert_eq!(
+            Key::from_legacy_redis_key("pcafe:tok:a:b:state")?,
+            (key, "state")
+        );
+        assert!(Key::from_legacy_redis_key("pcafe:{tok}:a:b:state").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn note_attribute() -> Result<()> {
+        let note = |v: &str| Update::from_query("tok", ("build.note".to_owned(), v.to_owned()));
+
+        let u = note("log at https://ci.example.com/runs/42?tab=log")?;
+        assert_eq!(
+            u.note,
+            Some(Some(
+                "log at https://ci.example.com/runs/42?tab=log".to_owned()
+            ))
+        );
+        assert_eq!((u.state, u.current, u.error), (None, None, None));
+
+        assert_eq!(note("null")?.note, Some(None));
+        assert!(note("<script>").is_err());
+        assert!(note(&"x".repeat(513)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_attribute() -> Result<()> {
+        let u = Update::from_query("tok", ("upload.error".to_owned(), "disk full".to_owned()))?;
+        assert_eq!(u.key.key, "upload");
+        assert_eq!(u.error, Some(Some("disk full".to_owned())));
+        assert_eq!((u.state, u.current, u.max), (None, None, None));
+
+        for clear in ["null", ""] {
+            let u = Update::from_query("tok", ("upload.error".to_owned(), clear.to_owned()))?;
+            assert_eq!(u.error, Some(None));
+        }
+
+        // Progress past an error leaves it alone
+        for progress in ["copying!60/100", "100/100", "done!"] {
+            let u = Update::from_query("tok", ("upload".to_owned(), progress.to_owned()))?;
+            assert_eq!(u.error, None);
+            assert!(!u.plan().set.contains_key("error"), "{}", progress);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn idempotency_records() -> Result<()> {
+        assert_eq!(Claim::of_record("f1\n", "f1"), Claim::Pending);
+        assert_eq!(
+            Claim::of_record("f1\nOK", "f1"),
+            Claim::Sent("OK".to_owned())
+        );
+        assert_eq!(
+            Claim::of_record("f1\n{\"a\":\n1}", "f1"),
+            Claim::Sent("{\"a\":\n1}".to_owned())
+        );
+        assert_eq!(Claim::of_record("f1\nOK", "f2"), Claim::Conflict);
+        assert_eq!(Claim::of_record("junk", "f1"), Claim::Conflict);
+
+        assert!(check_idempotency_key("4f1c-retry_2").is_ok());
+        for bad in ["", "a b", "é", &"x".repeat(256)] {
+            assert!(check_idempotency_key(bad).is_err(), "{:?}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_resets() -> Result<()> {
+        let reset = |val: &str| {
+            Update::from_query("tok", ("upload.resethistory".to_owned(), val.to_owned()))
+        };
+
+        let u = reset("1")?;
+        assert!(u.plan().reset_history);
+        assert!(u.changes().is_empty());
+        let del = redis::cmd("DEL")
+            .arg(u.key.redis_key("history"))
+            .get_packed_command();
+        assert!(u.as_cmds(60).any(|c| c.get_packed_command() == del));
+
+        assert!(
+            !Update::from_query("tok", ("upload".to_owned(), "5/10".to_owned()))?
+                .plan()
+                .reset_history
+        );
+        for bad in ["0", "yes", ""] {
+            assert!(reset(bad).is_err(), "{}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn array_style_keys() -> Result<()> {
+        let parse = |k: &str, v: &str| Update::from_query("tok", (k.to_owned(), v.to_owned()));
+
+        let u = parse("a[]", "5/10")?;
+        assert_eq!(u.key.key, "a");
+        assert_eq!((u.current, u.max), (Some(Some(5)), Some(Some(10))));
+
+        assert_eq!(parse("a[2]", "5/10")?.key.key, "a");
+        assert_eq!(parse("a.note[]", "hi")?.note, Some(Some("hi".to_owned())));
+        // Other brackets stay part of the name, which makes it a bad key
+        assert!(parse("a[x]", "5").is_err());
+        assert!(parse("[]", "5").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn meta_changes() -> Result<()> {
+        let query = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            TokenMeta::changes_from_query(&query(&[
+                ("title", "Nightly ETL — run 2024-06-01"),
+                ("description", " "),
+            ]))?,
+            [
+                ("title", Some("Nightly ETL — run 2024-06-01".to_owned())),
+                ("description", None),
+            ]
+        );
+
+        assert!(TokenMeta::changes_from_query(&query(&[("color", "red")])).is_err());
+        assert!(TokenMeta::changes_from_query(&query(&[("title", &"x".repeat(201))])).is_err());
+
+        let meta = TokenMeta {
+            strip_prefix: Some("myservice.prod.".to_owned()),
+            ..TokenMeta::default()
+        };
+        assert_eq!(meta.label("myservice.prod.build.compile"), "build.compile");
+        assert_eq!(meta.label("other.build"), "other.build");
+        assert_eq!(meta.label("myservice.prod."), "myservice.prod.");
+        assert_eq!(TokenMeta::default().label("a.b"), "a.b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn persist_attribute() -> Result<()> {
+        let persist =
+            |v: &str| Update::from_query("tok", ("audit.persist".to_owned(), v.to_owned()));
+
+        assert_eq!(persist("1")?.persist, Some(Some(1)));
+        assert_eq!(persist("1")?.changes(), [("persist", Some("1".to_owned()))]);
+        for off in ["0", "null", ""] {
+            assert_eq!(persist(off)?.persist, Some(None));
+        }
+        assert!(persist("yes").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn summary_counts() {
+        let value = |state: &str, current, error: Option<&str>| Value {
+            state: Some(state.to_owned()),
+            current: Some(current),
+            max: Some(10),
+            error: error.map(str::to_owned),
+            ..Value::default()
+        };
+        let values = [
+            value("linking", 10, None),
+            value("done", 3, None),
+            value("tests failed", 5, None),
+            value("uploading", 10, Some("disk full")),
+            value("Build FAILURE: see log", 0, None),
+            value("failover", 2, None),
+        ];
+
+        assert_eq!(
+            summarize(&values),
+            StatusSummary {
+                running: 1,
+                done: 2,
+                failed: 3,
+                total: 6,
+                percent: Some((100.0 + 100.0 + 50.0 + 100.0 + 0.0 + 20.0) / 6.0),
+                ..StatusSummary::default()
+            }
+        );
+        assert_eq!(summarize(&[]), StatusSummary::default());
+    }
+
+    #[test]
+    fn tags() -> Result<()> {
+        let tag = |v: &str| Update::from_query("tok", ("db.tag".to_owned(), v.to_owned()));
+        assert_eq!(
+            tag("critical, db,critical,")?.tags,
+            Some(Some("critical,db".to_owned()))
+        );
+        assert_eq!(tag("null")?.tags, Some(None));
+        assert_eq!(tag("")?.tags, Some(None));
+        assert!(tag("two words").is_err());
+        assert!(tag("a*").is_err());
+        assert!(tag(&Vec::from_iter((0..17).map(|i| i.to_string())).join(",")).is_err());
+
+        let value = |current, tags: &[&str]| Value {
+            current: Some(current),
+            max: Some(10),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Value::default()
+        };
+        let values = [
+            value(10, &["critical", "db"]),
+            value(5, &["critical"]),
+            value(0, &[]),
+        ];
+
+        let summary = summarize(&values);
+        assert_eq!(summary.total, 3);
+        assert_eq!(Vec::from_iter(summary.tags.keys()), ["critical", "db"]);
+        assert_eq!(
+            summary.tags["critical"],
+            StatusSummary {
+                running: 1,
+                done: 1,
+                total: 2,
+                percent: Some(75.0),
+                ..StatusSummary::default()
+            }
+        );
+        assert_eq!(summary.tags["db"].done, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn segments() -> Result<()> {
+        let segments =
+            |v: &str| Update::from_query("tok", ("jobs.segments".to_owned(), v.to_owned()));
+        assert_eq!(segments("5, 2,3")?.segments, Some(Some("5,2,3".to_owned())));
+        assert_eq!(segments("null")?.segments, Some(None));
+        assert!(segments("5,2").is_err());
+        assert!(segments("5,-2,3").is_err());
+        assert!(segments("5,x,3").is_err());
+
+        assert_eq!(
+            Segments::from_redis("5,2,3"),
+            Some(Segments {
+                done: 5,
+                active: 2,
+                pending: 3
+            })
+        );
+        assert_eq!(Segments::from_redis("5,2"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_rollups_sum_known_maxes() -> Result<()> {
+        let keys = [
+            Key::try_from(("tok", "build:compile"))?,
+            Key::try_from(("tok", "build:link"))?,
+            Key::try_from(("tok", "build:upload"))?,
+            Key::try_from(("tok", "test:unit"))?,
+            Key::try_from(("tok", "lonely"))?,
+        ];
+        let values = [
+            Value {
+                current: Some(5),
+                max: Some(10),
+                ..Value::default()
+            },
+            Value {
+                current: Some(20),
+                max: Some(10),
+                ..Value::default()
+            },
+            Value {
+                current: Some(7),
+                ..Value::default()
+            },
+            Value {
+                current: Some(3),
+                max: Some(0),
+                ..Value::default()
+            },
+            Value::default(),
+        ];
+
+        let groups = group_rollups(keys.iter().zip(&values));
+
+        assert_eq!(groups.keys().collect::<Vec<_>>(), ["build", "test"]);
+        assert_eq!(
+            groups["build"],
+            GroupRollup {
+                keys: 3,
+                current: 15,
+                max: 20,
+                percent: Some(75.0),
+            }
+        );
+        assert_eq!(groups["test"].percent, None);
+
+        let nested = [
+            Key::try_from(("tok", "import:users:fetch"))?,
+            Key::try_from(("tok", "import:users:validate"))?,
+            Key::try_from(("tok", "import:orders"))?,
+        ];
+        let nested = nested_rollups(nested.iter().zip(&values));
+        assert_eq!(
+            nested.keys().collect::<Vec<_>>(),
+            ["import", "import:users"]
+        );
+        assert_eq!((nested["import"].keys, nested["import"].max), (3, 20));
+        assert_eq!(nested["import:users"].percent, Some(75.0));
+        assert_eq!(
+            nested_rollups(keys.iter().zip(&values)),
+            group_rollups(keys.iter().zip(&values))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn etas_from_rates() {
+        let value = |current, max, rate| Value {
+            current: Some(current),
+            max,
+            rate_per_sec: rate,
+            ..Value::default()
+        };
+
+        assert_eq!(
+            value(10, Some(100), Some(4.0)).eta(),
+            Some(Eta::Seconds(23))
+        );
+        assert_eq!(value(10, Some(100), Some(0.0)).eta(), Some(Eta::Stalled));
+        assert_eq!(value(10, Some(100), Some(-2.0)).eta(), Some(Eta::Stalled));
+        assert_eq!(value(10, Some(100), None).eta(), None);
+        assert_eq!(value(10, None, Some(4.0)).eta(), None);
+        assert_eq!(value(100, Some(100), Some(4.0)).eta(), None);
+
+        let reversed = Value {
+            reverse: true,
+            ..value(30, Some(100), Some(-10.0))
+        };
+        assert_eq!(reversed.eta(), Some(Eta::Seconds(3)));
+    }
+
+    #[test]
+    fn changes_list_touched_params() -> Result<()> {
+        assert_eq!(
+            parse("compiling!/10")?.changes(),
+            [
+                ("state", Some("compiling".to_owned())),
+                ("max", Some("10".to_owned())),
+            ]
+        );
+        assert_eq!(
+            parse("null")?.changes(),
+            [("state", None), ("current", None)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn done_detection() {
+        let value = |state: Option<&str>, current, max| Value {
+            state: state.map(str::to_owned),
+            current,
+            max,
+            ..Value::default()
+        };
+
+        assert!(value(None, Some(100), Some(100)).is_done());
+        assert!(value(None, Some(120), Some(100)).is_done());
+        assert!(value(Some("Done"), Some(3), None).is_done());
+        assert!(!value(None, Some(99), Some(100)).is_done());
+        assert!(!value(None, Some(5), None).is_done());
+        assert!(!value(None, Some(0), Some(0)).is_done());
+        assert!(!value(None, Some(5), Some(0)).is_done());
+
+        let failed = Value {
+            error: Some("disk full".to_owned()),
+            ..value(None, Some(100), Some(100))
+        };
+        assert!(!failed.is_done());
+    }
+
+    #[test]
+    fn percent_of_one_value() {
+        let value = |current, max, state: Option<&str>| Value {
+            current,
+            max,
+            state: state.map(str::to_owned),
+            ..Value::default()
+        };
+
+        assert_eq!(value(Some(5), Some(20), None).percent(), Some(25.0));
+        assert_eq!(value(None, Some(20), None).percent(), Some(0.0));
+        assert_eq!(value(Some(5), None, None).percent(), None);
+        assert_eq!(value(Some(5), Some(0), None).percent(), None);
+        assert_eq!(value(Some(30), Some(20), None).percent(), Some(100.0));
+        assert_eq!(value(Some(-3), Some(20), None).percent(), Some(0.0));
+        assert_eq!(value(None, None, Some("done")).percent(), Some(100.0));
+        assert_eq!(
+            value(Some(1), Some(20), Some("Done")).percent(),
+            Some(100.0)
+        );
+
+        let failed = Value {
+            error: Some("oom".to_owned()),
+            ..value(Some(20), Some(20), None)
+        };
+        assert_eq!(failed.percent(), Some(100.0));
+    }
+
+    #[test]
+    fn queued_is_its_own_quadrant() {
+        let value = |current, max| Value {
+            current,
+            max,
+            ..Value::default()
+        };
+
+        // (current, max) present or not: indeterminate, queued, unscaled, running
+        let quadrants = [
+            (value(None, None), None, false),
+            (value(None, Some(1000)), Some(0.0), true),
+            (value(Some(5), None), None, false),
+            (value(Some(250), Some(1000)), Some(25.0), false),
+        ];
+
+        for (v, percent, queued) in quadrants {
+            assert_eq!((v.percent(), v.is_queued()), (percent, queued), "{:?}", v);
+        }
+
+        assert!(!value(None, Some(0)).is_queued());
+        assert!(!Value {
+            state: Some("done".to_owned()),
+            ..value(None, Some(10))
+        }
+        .is_queued());
+        assert!(!Value {
+            error: Some("never started".to_owned()),
+            ..value(None, Some(10))
+        }
+        .is_queued());
+    }
+
+    #[test]
+    fn percent_precision() -> Result<()> {
+        assert_eq!(format_percent(41.66, 0), "41");
+        assert_eq!(format_percent(41.66, 1), "41.6");
+        assert_eq!(format_percent(0.29, 2), "0.29");
+        assert_eq!(format_percent(99.9999, 3), "99.999");
+        assert_eq!(format_percent(100.0, 2), "100.00");
+        assert_eq!(round_percent(41.66, 1), 41.6);
+
+        assert_eq!(parse_precision(" 3")?, 3);
+        for bad in ["4", "-1", "", "one"] {
+            assert!(parse_precision(bad).is_err(), "{}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn overall_percent_skips_unknown_max() {
+        let value = |current, max| Value {
+            current,
+            max,
+            ..Value::default()
+        };
+
+        let values = [
+            value(Some(5), Some(10)),
+            value(None, Some(30)),
+            value(Some(7), None),
+        ];
+        // 50% and 0%, the unknown max doesn't count
+        assert_eq!(overall_percent(&values), Some(25.0));
+
+        assert_eq!(overall_percent(&[value(Some(7), None)]), None);
+        assert_eq!(overall_percent(&[value(Some(0), Some(0))]), None);
+        assert_eq!(overall_percent(&[value(Some(5), Some(0))]), None);
+        assert_eq!(
+            overall_percent(&[value(Some(5), Some(0)), value(Some(1), Some(4))]),
+            Some(25.0)
+        );
+        assert_eq!(overall_percent(&[]), None);
+    }
+
+    #[test]
+    fn weighted_overall_percent() -> Result<()> {
+        let value = |current, max, weight| Value {
+            current,
+            max,
+            weight,
+            ..Value::default()
+        };
+
+        // The upload is 70% of the work, halfway through it is 35% overall
+        let values = [
+            value(Some(10), Some(10), Some(0.75)),
+            value(Some(10), Some(10), Some(0.75)),
+            value(Some(0), Some(10), Some(0.75)),
+            value(Some(0), Some(10), Some(0.75)),
+            value(Some(50), Some(100), Some(7.0)),
+        ];
+        assert_eq!(overall_percent(&values), Some((150.0 + 350.0) / 10.0));
+
+        // Unknown maxes are out, weight and all
+        let values = [
+            value(Some(5), None, Some(100.0)),
+            value(Some(1), Some(4), None),
+        ];
+        assert_eq!(overall_percent(&values), Some(25.0));
+
+        let weight =
+            |w: &str| Update::from_query("tok", ("upload.weight".to_owned(), w.to_owned()));
+        assert_eq!(weight("7")?.weight, Some(Some(7.0)));
+        assert_eq!(weight("0.5")?.weight, Some(Some(0.5)));
+        assert_eq!(weight("null")?.weight, Some(None));
+        for bad in ["0", "-1", "NaN", "inf", "heavy"] {
+            assert!(weight(bad).is_err(), "{}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn counters_have_no_percent() -> Result<()> {
+        let metric = |v: &str| Update::from_query("tok", ("events.type".to_owned(), v.to_owned()));
+        assert_eq!(metric("counter")?.metric, Some(Some("counter".to_owned())));
+        assert_eq!(metric("progress")?.metric, Some(None));
+        assert_eq!(metric("null")?.metric, Some(None));
+        assert!(metric("gauge").is_err());
+
+        let counter = |current| Value {
+            current,
+            max: Some(100),
+            metric: Metric::Counter,
+            ..Value::default()
+        };
+        assert_eq!(counter(Some(1_200_000)).percent(), None);
+        assert!(!counter(Some(100)).is_done());
+        assert!(!counter(None).is_queued());
+        assert!(Value {
+            state: Some("done".to_owned()),
+            ..counter(Some(5))
+        }
+        .is_done());
+
+        let progress = Value {
+            current: Some(5),
+            max: Some(10),
+            ..Value::default()
+        };
+        assert_eq!(overall_percent(&[counter(Some(100)), progress]), Some(50.0));
+
+        assert_eq!(serde_json::to_value(counter(Some(1)))?["type"], "counter");
+        assert_eq!(serde_json::to_value(Value::default())?.get("type"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reverse_keys_count_down() -> Result<()> {
+        let reverse =
+            |v: &str| Update::from_query("tok", ("left.reverse".to_owned(), v.to_owned()));
+        assert_eq!(reverse("1")?.reverse, Some(Some(1)));
+        assert_eq!(reverse("0")?.reverse, Some(None));
+        assert!(reverse("2").is_err());
+
+        let left = |current| Value {
+            current,
+            max: Some(20),
+            reverse: true,
+            ..Value::default()
+        };
+        assert_eq!(left(Some(15)).percent(), Some(25.0));
+        assert_eq!(left(Some(25)).percent(), Some(0.0));
+        assert_eq!(left(None).percent(), Some(0.0));
+        assert!(left(None).is_queued());
+
+        assert!(!left(Some(20)).is_done());
+        assert!(!left(Some(1)).is_done());
+        assert!(left(Some(0)).is_done());
+        assert!(left(Some(-1)).is_done());
+        assert_eq!(left(Some(0)).percent(), Some(100.0));
+
+        // Done at 0 even without a max
+        let no_max = Value {
+            max: None,
+            ..left(Some(0))
+        };
+        assert!(no_max.is_done());
+
+        let forward = Value {
+            current: Some(15),
+            max: Some(20),
+            ..Value::default()
+        };
+        let values = [left(Some(15)), forward];
+        assert_eq!(overall_percent(&values), Some(50.0));
+        assert_eq!(summarize(&values).done, 0);
+
+        let a = Key::try_from(("tok", "g:a"))?;
+        let b = Key::try_from(("tok", "g:b"))?;
+        let rollup = &group_rollups([(&a, &values[0]), (&b, &values[1])])["g"];
+        assert_eq!((rollup.current, rollup.max), (20, 40));
+
+        assert_eq!(serde_json::to_value(left(Some(1)))?["reverse"], true);
+
+        Ok(())
+    }
+
+    #[test]
+    fn icons() -> Result<()> {
+        let icon = |v: &str| Update::from_query("tok", ("build.icon".to_owned(), v.to_owned()));
+        assert_eq!(icon("🚀")?.icon, Some(Some("🚀".to_owned())));
+        assert_eq!(icon("👨‍👩‍👧")?.icon, Some(Some("👨‍👩‍👧".to_owned())));
+        assert_eq!(icon("deploy")?.icon, Some(Some("deploy".to_owned())));
+        assert_eq!(icon("null")?.icon, Some(None));
+        for bad in [
+            "rocket",
+            "<b>",
+            "🚀<",
+            "🚀 🚀",
+            "\u{7}",
+            "🚀🚀🚀🚀🚀🚀🚀🚀🚀",
+        ] {
+            assert!(icon(bad).is_err(), "{:?}", bad);
+        }
+
+        let value = |icon: &str| Value {
+            icon: Some(icon.to_owned()),
+            ..Value::default()
+        };
+        assert_eq!(value("deploy").icon(), Some("🚀"));
+        assert_eq!(value("🧵").icon(), Some("🧵"));
+        assert_eq!(serde_json::to_value(value("deploy"))?["icon"], "deploy");
+
+        Ok(())
+    }
+
+    #[test]
+    fn indeterminate_keys_are_left_out() -> Result<()> {
+        let indeterminate =
+            |v: &str| Update::from_query("tok", ("scan.indeterminate".to_owned(), v.to_owned()));
+        assert_eq!(indeterminate("1")?.indeterminate, Some(Some(1)));
+        assert_eq!(indeterminate("0")?.indeterminate, Some(None));
+        assert_eq!(indeterminate("null")?.indeterminate, Some(None));
+        assert!(indeterminate("yes").is_err());
+
+        let value = |current, indeterminate| Value {
+            current: Some(current),
+            max: Some(10),
+            indeterminate,
+            ..Value::default()
+        };
+        let spinning = value(10, true);
+        assert!(!spinning.is_done());
+        assert_eq!(spinning.percent(), None);
+        let declared = Value {
+            current: None,
+            ..spinning.clone()
+        };
+        assert!(!declared.is_queued());
+
+        let done = Value {
+            state: Some("done".to_owned()),
+            ..spinning.clone()
+        };
+        assert!(done.is_done());
+
+        let values = [value(5, false), spinning.clone()];
+        assert_eq!(overall_percent(&values), Some(50.0));
+        assert_eq!(summarize(&values).done, 0);
+
+        let a = Key::try_from(("tok", "g:a"))?;
+        let b = Key::try_from(("tok", "g:b"))?;
+        let rollup = &group_rollups([(&a, &values[0]), (&b, &values[1])])["g"];
+        assert_eq!(
+            (rollup.keys, rollup.current, rollup.max, rollup.percent),
+            (2, 5, 10, Some(50.0))
+        );
+
+        let json = serde_json::to_value(&spinning)?;
+        assert_eq!(json["indeterminate"], true);
+        assert_eq!(
+            serde_json::to_value(value(5, false))?.get("indeterminate"),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn timed_keys() -> Result<()> {
+        let duration =
+            |d: &str| Update::from_query("tok", ("deploy.duration".to_owned(), d.to_owned()));
+        assert_eq!(duration("600")?.duration, Some(Some(600)));
+        assert_eq!(duration("null")?.duration, Some(None));
+        for bad in ["0", "-5", "soon"] {
+            assert!(duration(bad).is_err(), "{}", bad);
+        }
+
+        let value = Value {
+            created_at: Some(1000),
+            duration: Some(600),
+            ..Value::default()
+        };
+        assert_eq!(value.percent(), None);
+        assert_eq!(value.clone().timed(1150).percent(), Some(25.0));
+
+        let late = value.clone().timed(1700);
+        assert_eq!(late.percent(), Some(100.0));
+        assert!(late.timed.unwrap().overtime);
+        assert!(!late.is_done());
+
+        let json = serde_json::to_value(&late)?;
+        assert_eq!(json["mode"], "time");
+        assert_eq!(json["elapsed"], 700);
+        assert_eq!(serde_json::to_value(&value)?.get("mode"), None);
+
+        // Once there's a max, the counts are what the bar goes by
+        let counted = Value {
+            current: Some(1),
+            max: Some(4),
+            ..value.clone()
+        };
+        assert_eq!(counted.timed(1700).percent(), Some(25.0));
+
+        let finished = Value {
+            state: Some("done".to_owned()),
+            ..value
+        };
+        assert!(!finished.timed(1700).timed.unwrap().overtime);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn it_works() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let conm = ConnectionManager::new(client).await?;
+
+        let store = Store::new(conm);
+
+        store
+            .update(&Update::new(
+                ("randomtoken", "some:key").try_into()?,
+                None,
+                Some(Some(0)),
+                Some(Some(10)),
+            ))
+            .await?;
+
+        let keys = store.get_all_keys("randomtoken", "some:").await?;
+
+        println!("get_all_keys {:?}", &keys);
+
+        for key in keys {
+            let st = store.get_state(&key).await?;
+            println!("{:?} .. {:?}", key, st);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn count_follows_creates_and_deletes() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = |k| Key::try_from(("counttoken", k)).unwrap();
+        let create = |k| Update::builder(key(k)).current(1).build();
+
+        for k in ["a:one", "a:two", "b"] {
+            store.delete_key(&key(k)).await?;
+        }
+        assert_eq!(store.count_keys("counttoken", "").await?, 0);
+
+        store.update(&create("a:one")).await?;
+        store.update(&create("a:two")).await?;
+        store.update(&create("a:two")).await?;
+        store.update(&create("b")).await?;
+        assert_eq!(store.count_keys("counttoken", "").await?, 3);
+        assert_eq!(store.count_keys("counttoken", "a:").await?, 2);
+
+        store.delete_key(&key("a:one")).await?;
+        assert_eq!(store.count_keys("counttoken", "").await?, 2);
+        assert_eq!(store.count_keys("counttoken", "a:").await?, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn counter_from_scratch() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("counttoken", "fresh:counter"))?;
+        store.delete_key(&key).await?;
+
+        let bump = Update::from_query(
+            "counttoken",
+            ("fresh:counter".to_owned(), "+1/100".to_owned()),
+        )?;
+        store.update(&bump).await?;
+        store.update(&bump).await?;
+
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (Some(2), Some(100)));
+        assert!(value.created_at.is_some());
+
+        let ttl: i64 = store.redis.clone().ttl(key.redis_key("current")).await?;
+        assert!(ttl > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn set_max_clamps_current() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("setmaxtoken", "files"))?;
+        store.delete_key(&key).await?;
+
+        let send =
+            |val: &str| Update::from_query("setmaxtoken", ("files".to_owned(), val.to_owned()));
+        let set_max = |max: &str| {
+            Update::from_query("setmaxtoken", ("files.setmax".to_owned(), max.to_owned()))
+        };
+
+        store.update(&send("copying!80/100")?).await?;
+
+        store.update(&set_max("400")?).await?;
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (Some(80), Some(400)));
+        assert_eq!(value.percent(), Some(20.0));
+
+        store.update(&set_max("50")?).await?;
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (Some(50), Some(50)));
+        assert_eq!(value.state.as_deref(), Some("copying"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn errors_stick_through_updates() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("stickytoken", "upload"))?;
+        store.delete_key(&key).await?;
+
+        let send = |field: &str, val: &str| {
+            Update::from_query("stickytoken", (field.to_owned(), val.to_owned()))
+        };
+
+        store.update(&send("upload", "copying!10/100")?).await?;
+        store.update(&send("upload.error", "disk full")?).await?;
+        store.update(&send("upload", "60/100")?).await?;
+        store.update(&send("upload", "+40")?).await?;
+
+        let value = store.get_state(&key).await?;
+        assert_eq!(value.error.as_deref(), Some("disk full"));
+        assert_eq!(value.current, Some(100));
+        assert!(value.is_failed() && !value.is_done());
+
+        store.update(&send("upload.error", "null")?).await?;
+        let value = store.get_state(&key).await?;
+        assert_eq!(value.error, None);
+        assert!(value.is_done());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn retried_increments_apply_once() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("idemtoken", "rows"))?;
+        store.delete_key(&key).await?;
+        store.release_send("idemtoken", "batch-7").await?;
+
+        // What /send does with an Idempotency-Key
+        let send = || async {
+            match store.claim_send("idemtoken", "batch-7", "f1").await? {
+                Claim::Claimed => {
+                    let update =
+                        Update::from_query("idemtoken", ("rows".to_owned(), "+5".to_owned()))?;
+                    store.update(&update).await?;
+                    store
+                        .finish_send("idemtoken", "batch-7", "f1", "OK")
+                        .await?;
+
+                    anyhow::Ok("OK".to_owned())
+                }
+                Claim::Sent(body) => Ok(body),
+                claim => Err(anyhow!("unexpected {:?}", claim)),
+            }
+        };
+
+        assert_eq!(send().await?, "OK");
+        assert_eq!(send().await?, "OK");
+        assert_eq!(store.get_state(&key).await?.current, Some(5));
+
+        assert_eq!(
+            store.claim_send("idemtoken", "batch-7", "f2").await?,
+            Claim::Conflict
+        );
+        assert_eq!(
+            store.claim_send("othertoken", "batch-7", "f2").await?,
+            Claim::Claimed
+        );
+        store.release_send("othertoken", "batch-7").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn history_resets_keep_the_key() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("resettoken", "upload"))?;
+        store.delete_key(&key).await?;
+
+        let send = |field: &str, val: &str| {
+            Update::from_query("resettoken", (field.to_owned(), val.to_owned()))
+        };
+
+        for val in ["uploading!10/100", "50/100", "100/100"] {
+            store.update(&send("upload", val)?).await?;
+        }
+        assert_eq!(store.get_history(&key).await?.len(), 3);
+
+        store.update(&send("upload.resethistory", "1")?).await?;
+        assert_eq!(store.get_history(&key).await?, []);
+
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (Some(100), Some(100)));
+        assert_eq!(value.state.as_deref(), Some("uploading"));
+
+        store.update(&send("upload", "5/100")?).await?;
+        assert_eq!(store.get_history(&key).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn init_only_creates_missing_keys() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("inittoken", "job"))?;
+        store.delete_key(&key).await?;
+
+        let send = |val: &str| Update::from_query("inittoken", ("job".to_owned(), val.to_owned()));
+
+        assert!(store.init_if_absent(&send("starting!0/100")?).await?);
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (Some(0), Some(100)));
+        assert!(value.created_at.is_some());
+        assert_eq!(store.get_all_keys("inittoken", "").await?.len(), 1);
+
+        store.update(&send("copying!40")?).await?;
+        assert!(!store.init_if_absent(&send("starting!0/100")?).await?);
+        let value = store.get_state(&key).await?;
+        assert_eq!(value.state.as_deref(), Some("copying"));
+        assert_eq!(value.current, Some(40));
+
+        assert!(store.init_if_absent(&send("+1")?).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn debug_shows_raw_params() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = Key::try_from(("debugtoken", "k"))?;
+        store.delete_key(&key).await?;
+        store
+            .update(&Update::from_query(
+                "debugtoken",
+                ("k".to_owned(), "5/10".to_owned()),
+            )?)
+            .await?;
+
+        let raw = store.debug_key(&key).await?;
+
+        let current = &raw[&key.redis_key("current")];
+        assert_eq!(
+            (current.kind.as_str(), &current.value),
+            ("string", &serde_json::json!("5"))
+        );
+        assert!(current.ttl > 0);
+        assert_eq!(raw[&key.redis_key("history")].kind, "list");
+        assert_eq!(raw[&Key::index_key("debugtoken")].kind, "zset");
+        assert!(!raw.contains_key(&key.redis_key("note")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn exports_restore_in_place() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        for key in store.get_all_keys("exporttoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let key = |k: &str| Key::try_from(("exporttoken", k));
+        let send =
+            |k: &str, v: &str| Update::from_query("exporttoken", (k.to_owned(), v.to_owned()));
+        store
+            .update_many(&[
+                send("build", "linking!7/10")?,
+                send("build.tag", "ci,nightly")?,
+                send("build.segments", "5,2,3")?,
+                send("test", "1/4")?,
+            ])
+            .await?;
+
+        let exported = store.export_token("exporttoken").await?;
+        assert_eq!(
+            Vec::from_iter(exported.iter().map(|e| e.key.as_str())),
+            ["build", "test"]
+        );
+        assert!(exported[0].pttl.is_some_and(|ms| ms > 0));
+
+        store.delete_key(&key("build")?).await?;
+        store.update(&send("test", "4/4")?).await?;
+
+        assert_eq!(store.restore(&exported).await?, 2);
+        assert_eq!(store.get_state(&key("build")?).await?, exported[0].value);
+        assert_eq!(store.get_state(&key("test")?).await?.current, Some(1));
+        assert_eq!(store.count_keys("exporttoken", "").await?, 2);
+
+        let foreign = ExportedKey {
+            token: "bad token".to_owned(),
+            ..store.export_token("exporttoken").await?.remove(0)
+        };
+        assert!(store.restore(&[foreign]).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn views_point_at_their_token() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        assert_eq!(store.view_of("view-nosuchview").await?, None);
+
+        store.remember_view("view-abc", "viewtoken").await?;
+        assert_eq!(
+            store.view_of("view-abc").await?.as_deref(),
+            Some("viewtoken")
+        );
+        assert!(store.remember_view("view-abc", "bad token").await.is_err());
+
+        // Only rewritten once it's past half its TTL
+        let mut redis = store.redis.clone();
+        let view = Key::view_key("view-abc");
+        redis.expire::<_, ()>(&view, 10).await?;
+        store.set_expire_seconds(15);
+        store.remember_view("view-abc", "viewtoken").await?;
+        assert_eq!(redis.ttl::<_, i64>(&view).await?, 10);
+        store.set_expire_seconds(30);
+        store.remember_view("view-abc", "viewtoken").await?;
+        assert!(redis.ttl::<_, i64>(&view).await? > 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn clear_deletes_by_prefix() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        store.clear("cleartoken", "").await?;
+
+        let send = |k: &str| Update::from_query("cleartoken", (k.to_owned(), "1/2".to_owned()));
+        store
+            .update_many(&[send("build:eu")?, send("build:us")?, send("test")?])
+            .await?;
+
+        assert_eq!(store.clear("cleartoken", "build:").await?, 2);
+        let keys = store.get_all_keys("cleartoken", "").await?;
+        assert_eq!(
+            Vec::from_iter(keys),
+            [Key::try_from(("cleartoken", "test"))?]
+        );
+        assert_eq!(store.count_keys("cleartoken", "").await?, 1);
+
+        assert_eq!(store.clear("cleartoken", "").await?, 1);
+        assert_eq!(store.clear("cleartoken", "").await?, 0);
+        assert_eq!(store.clear("nosuchtoken", "").await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn declare_replaces_the_keys() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        for key in store.get_all_keys("declaretoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let key = |k: &str| Key::try_from(("declaretoken", k));
+        let send =
+            |k: &str, v: &str| Update::from_query("declaretoken", (k.to_owned(), v.to_owned()));
+        store
+            .update_many(&[send("build", "7/10")?, send("straggler", "3/3")?])
+            .await?;
+
+        let declared = store
+            .declare(
+                "declaretoken",
+                &[
+                    (key("build")?, Some(100)),
+                    (key("test")?, Some(20)),
+                    (key("lint")?, None),
+                ],
+            )
+            .await?;
+        assert_eq!(
+            declared,
+            Declared {
+                added: 2,
+                removed: 1,
+                kept: 1
+            }
+        );
+
+        let mut keys = Vec::from_iter(store.get_all_keys("declaretoken", "").await?);
+        keys.sort();
+        assert_eq!(keys, [key("build")?, key("lint")?, key("test")?]);
+
+        let build = store.get_state(&key("build")?).await?;
+        assert_eq!((build.current, build.max), (Some(7), Some(10)));
+        let test = store.get_state(&key("test")?).await?;
+        assert_eq!((test.current, test.max), (Some(0), Some(20)));
+        assert!(store.get_state(&key("straggler")?).await?.is_empty());
+
+        let other = Key::try_from(("othertoken", "build"))?;
+        assert!(store
+            .declare("declaretoken", &[(other, None)])
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn clone_copies_every_key() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        for token in ["clonefrom", "cloneto"] {
+            for key in store.get_all_keys(token, "").await? {
+                store.delete_key(&key).await?;
+            }
+        }
+
+        for (k, v) in [("a", "compiling!5/10"), ("b:c", "7")] {
+            store
+                .update(&Update::from_query(
+                    "clonefrom",
+                    (k.to_owned(), v.to_owned()),
+                )?)
+                .await?;
+        }
+
+        assert!(store.clone_token("clonefrom", "clonefrom").await.is_err());
+        assert_eq!(store.clone_token("clonefrom", "cloneto").await?, 2);
+
+        let from = store.get_all_states("clonefrom", "").await?;
+        let to = store.get_all_states("cloneto", "").await?;
+        assert_eq!(
+            from.iter().map(|(k, v)| (&k.key, v)).collect::<Vec<_>>(),
+            to.iter().map(|(k, v)| (&k.key, v)).collect::<Vec<_>>()
+        );
+        assert_eq!(store.count_keys("cloneto", "").await?, 2);
+
+        let ttl: i64 = store
+            .redis
+            .clone()
+            .ttl(Key::try_from(("cloneto", "a"))?.redis_key("state"))
+            .await?;
+        assert!(ttl > 0);
+
+        // A persisted key is indexed for good in the clone too
+        let done = [("a.persist", "1"), ("a", "done!10/10")]
+            .map(|(k, v)| Update::from_query("clonefrom", (k.to_owned(), v.to_owned())));
+        store
+            .update_many(&done.into_iter().collect::<Result<Vec<_>>>()?)
+            .await?;
+        store.clone_token("clonefrom", "cloneto").await?;
+        let score: Option<String> = store
+            .redis
+            .clone()
+            .zscore(Key::index_key("cloneto"), "a")
+            .await?;
+        assert_eq!(score.as_deref(), Some("inf"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn index_follows_creates_and_deletes() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+        let mut redis = store.redis.clone();
+
+        for key in store.get_all_keys("indextoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let names = |keys: HashSet<Key>| {
+            let mut names = Vec::from_iter(keys.into_iter().map(|k| k.key));
+            names.sort();
+            names
+        };
+
+        for k in ["a:one", "a:two"] {
+            store
+                .update(&Update::from_query(
+                    "indextoken",
+                    (k.to_owned(), "1".to_owned()),
+                )?)
+                .await?;
+        }
+        assert_eq!(
+            names(store.get_all_keys("indextoken", "").await?),
+            ["a:one", "a:two"]
+        );
+
+        store
+            .delete_key(&Key::try_from(("indextoken", "a:one"))?)
+            .await?;
+        assert_eq!(
+            names(store.get_all_keys("indextoken", "").await?),
+            ["a:two"]
+        );
+
+        // Gone around the store, so listed until the index expires it, but
+        // without a state
+        let two = Key::try_from(("indextoken", "a:two"))?;
+        redis
+            .del::<_, ()>(KEY_PARAMS.map(|p| two.redis_key(p)).to_vec())
+            .await?;
+        assert_eq!(
+            names(store.get_all_keys("indextoken", "").await?),
+            ["a:two"]
+        );
+        assert!(store.get_all_states("indextoken", "").await?.is_empty());
+
+        // Written before keys were hash-tagged, so under the old names and
+        // not indexed
+        store
+            .delete_key(&Key::try_from(("indextoken", "a:two"))?)
+            .await?;
+        redis
+            .set_ex::<_, _, ()>("pcafe:indextoken:legacy:current", 4, 60)
+            .await?;
+        redis
+            .set_ex::<_, _, ()>("pcafe:indextoken:legacy:max", 8, 60)
+            .await?;
+
+        let no_scan = store.clone();
+        let store = store.with_legacy_scan(true);
+        assert!(no_scan.get_all_keys("indextoken", "").await?.is_empty());
+        assert_eq!(
+            names(store.get_all_keys("indextoken", "").await?),
+            ["legacy"]
+        );
+        assert_eq!(
+            names(no_scan.get_all_keys("indextoken", "").await?),
+            ["legacy"]
+        );
+
+        // Moved to the new names, where they're read
+        let legacy = Key::try_from(("indextoken", "legacy"))?;
+        let value = store.get_state(&legacy).await?;
+        assert_eq!((value.current, value.max), (Some(4), Some(8)));
+        assert!(
+            !redis
+                .exists::<_, bool>("pcafe:indextoken:legacy:current")
+                .await?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn done_percents() {
+        for (current, at_99, at_100) in [(98, false, false), (99, true, false), (100, true, true)] {
+            assert_eq!(reaches_percent(current, 100, 99), at_99, "{}", current);
+            assert_eq!(reaches_percent(current, 100, 100), at_100, "{}", current);
+        }
+        assert!(reaches_percent(990, 1000, 99));
+        assert!(!reaches_percent(5, 0, 99));
+        assert!(reaches_percent(i64::MAX, i64::MAX, 100));
+
+        assert!(set_done_percent(0).is_err());
+        assert!(set_done_percent(101).is_err());
+    }
+
+    #[test]
+    fn gc_garbage_by_name() -> Result<()> {
+        assert_eq!(
+            gc_parse("pcafe:{tok}:build:eu:current"),
+            Ok(Key::try_from(("tok", "build:eu"))?)
+        );
+        assert_eq!(
+            gc_parse("pcafe:tok:build:current"),
+            Err(Garbage::Unparseable)
+        );
+        assert_eq!(gc_parse("pcafe:{tok}:current"), Err(Garbage::Unparseable));
+        assert_eq!(
+            gc_parse("pcafe:{tok}:build:progress"),
+            Err(Garbage::UnknownParam)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unparseable_params_read_as_missing() -> Result<()> {
+        let key = Key::try_from(("tok", "build"))?;
+        let mut raw = vec![redis::Value::Nil; VALUE_PARAMS.len()];
+        raw[1] = redis::Value::Data(b"lots".to_vec());
+        raw[2] = redis::Value::Data(b"10".to_vec());
+        raw[9] = redis::Value::Bulk(vec![]);
+
+        let value = Value::from_redis(&key, &raw);
+        assert_eq!(
+            (value.current, value.max, value.weight),
+            (None, Some(10), None)
+        );
+        assert_eq!(value.percent(), Some(0.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn corrupt_keys_still_read() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let mut redis = ConnectionManager::new(client).await?;
+        let store = Store::new(redis.clone());
+        let key = Key::try_from(("corrupttoken", "build"))?;
+
+        store
+            .update(&Update::from_query(
+                "corrupttoken",
+                ("build".to_owned(), "3/10".to_owned()),
+            )?)
+            .await?;
+        redis::cmd("SET")
+            .arg(key.redis_key("current"))
+            .arg("lots")
+            .query_async::<_, ()>(&mut redis)
+            .await?;
+
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (None, Some(10)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn gc_keeps_live_keys() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let mut redis = ConnectionManager::new(client).await?;
+        let store = Store::new(redis.clone());
+
+        store
+            .update(&Update::from_query(
+                "gctoken",
+                ("build".to_owned(), "1/10".to_owned()),
+            )?)
+            .await?;
+        for junk in [
+            "pcafe:gctoken:build:current",
+            "pcafe:{gctoken}:build:progress",
+            "pcafe:{gctoken}:gone:current",
+        ] {
+            redis::cmd("SET")
+                .arg(junk)
+                .arg("1")
+                .query_async::<_, ()>(&mut redis)
+                .await?;
+        }
+
+        let dry = store.gc(true).await?;
+        assert!(dry.unparseable >= 1 && dry.unknown_param >= 1 && dry.orphaned >= 1);
+        assert_eq!(dry.deleted, 0);
+
+        let report = store.gc(false).await?;
+        assert!(report.deleted >= 3, "{:?}", report);
+        assert_eq!(
+            store
+                .get_state(&Key::try_from(("gctoken", "build"))?)
+                .await?
+                .current,
+            Some(1)
+        );
+        assert_eq!(store.gc(true).await?.orphaned, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn migrate_moves_old_names() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let mut redis = ConnectionManager::new(client).await?;
+        let store = Store::new(redis.clone());
+
+        for key in store.get_all_keys("migratetoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+        redis
+            .set_ex::<_, _, ()>("pcafe:migratetoken:build:current", 3, 600)
+            .await?;
+        redis
+            .set_ex::<_, _, ()>("pcafe:migratetoken:build:max", 10, 600)
+            .await?;
+
+        let dry = store.migrate(true).await?;
+        assert!(dry.moved >= 2 && dry.keys >= 1, "{:?}", dry);
+        assert!(store.get_all_keys("migratetoken", "").await?.is_empty());
+
+        store.migrate(false).await?;
+        let key = Key::try_from(("migratetoken", "build"))?;
+        assert_eq!(
+            store.get_all_keys("migratetoken", "").await?,
+            HashSet::from([key.clone()])
+        );
+        let value = store.get_state(&key).await?;
+        assert_eq!((value.current, value.max), (Some(3), Some(10)));
+        assert!(redis.ttl::<_, i64>(key.redis_key("max")).await? > 0);
+        assert!(
+            !redis
+                .exists::<_, bool>("pcafe:migratetoken:build:max")
+                .await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn prefixes_are_touched_alone() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+        store.set_expire_seconds(600);
+
+        for key in store.get_all_keys("stagetoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let send = |k: &str| Update::from_query("stagetoken", (k.to_owned(), "1/10".to_owned()));
+        store
+            .update_many(&[send("stageA:x")?, send("stageB:x")?, send("stageB:y")?])
+            .await?;
+
+        assert_eq!(
+            store
+                .touch_prefix("stagetoken", "stageB:", Some(3600))
+                .await?,
+            2
+        );
+        assert!(store
+            .touch_prefix("stagetoken", "stage B:", None)
+            .await
+            .is_err());
+
+        for (key, pttl) in store.get_all_keys_with_ttl("stagetoken", "").await? {
+            let pttl = pttl.unwrap();
+            match key.key.as_str() {
+                "stageA:x" => assert!(pttl <= 600_000, "{}", pttl),
+                _ => assert!(pttl > 3_590_000, "{} {}", key.key, pttl),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn fresh_keys_report_their_ttl() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+        store.set_expire_seconds(600);
+
+        for key in store.get_all_keys("ttltoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let send = |k: &str, v: &str| Update::from_query("ttltoken", (k.to_owned(), v.to_owned()));
+        store
+            .update_many(&[
+                send("build:a", "1/10")?,
+                send("build:b", "2/10")?,
+                send("lint", "3")?,
+                send("long", "+1/10")?.with_ttl(Some(86_400)),
+            ])
+            .await?;
+
+        let ttls = store.get_all_keys_with_ttl("ttltoken", "build:").await?;
+        assert_eq!(
+            Vec::from_iter(ttls.iter().map(|(k, _)| k.key.as_str())),
+            ["build:a", "build:b"]
+        );
+        for (key, pttl) in &ttls {
+            let pttl = pttl.unwrap_or_else(|| panic!("{} has no TTL", key.key));
+            assert!(pttl > 590_000 && pttl <= 600_000, "{}", pttl);
+        }
+
+        // A longer `_ttl` keeps the key, its history and its index entry
+        let long = store.get_all_keys_with_ttl("ttltoken", "long").await?;
+        let pttl = long[0].1.expect("long has a TTL");
+        assert!(pttl > 86_390_000 && pttl <= 86_400_000, "{}", pttl);
+        let history_ttl: i64 = store
+            .redis
+            .clone()
+            .ttl(Key::try_from(("ttltoken", "long"))?.redis_key("history"))
+            .await?;
+        assert!(history_ttl > 86_390, "{}", history_ttl);
+
+        // Nor does a shorter one after it cut what the token's keys share
+        store
+            .set_meta("ttltoken", &[("title", Some("long".to_owned()))])
+            .await?;
+        store
+            .update(&send("short", "1/10")?.with_ttl(Some(60)))
+            .await?;
+        for shared in [Key::index_key("ttltoken"), Key::meta_key("ttltoken")] {
+            let ttl: i64 = store.redis.clone().ttl(&shared).await?;
+            assert!(ttl > 86_390, "{} {}", shared, ttl);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn persisted_keys_outlive_the_ttl() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+        store.set_expire_seconds(1);
+
+        for key in store.get_all_keys("persisttoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let send =
+            |k: &str, v: &str| Update::from_query("persisttoken", (k.to_owned(), v.to_owned()));
+        store
+            .update_many(&[
+                send("kept.persist", "1")?,
+                send("kept", "5/10")?,
+                send("expiring", "5/10")?,
+            ])
+            .await?;
+
+        let kept = Key::try_from(("persisttoken", "kept"))?;
+        let ttl: i64 = store.redis.clone().ttl(kept.redis_key("current")).await?;
+        assert!(ttl > 0, "not done yet, so not persisted");
+
+        store
+            .update_many(&[send("kept", "10/10")?, send("expiring", "10/10")?])
+            .await?;
+
+        tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+
+        let ttl: i64 = store.redis.clone().ttl(kept.redis_key("current")).await?;
+        assert_eq!(ttl, -1);
+        assert_eq!(store.get_state(&kept).await?.current, Some(10));
+
+        let keys = store.get_all_keys("persisttoken", "").await?;
+        assert_eq!(Vec::from_iter(keys), std::slice::from_ref(&kept));
+
+        store.delete_key(&kept).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn frozen_tokens_outlive_the_ttl() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+        store.set_expire_seconds(1);
+
+        for key in store.get_all_keys("frozentoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+
+        let send =
+            |k: &str, v: &str| Update::from_query("frozentoken", (k.to_owned(), v.to_owned()));
+        store.update(&send("before", "5/10")?).await?;
+
+        assert_eq!(store.freeze("frozentoken").await?, 1);
+        assert!(store.is_frozen("frozentoken").await?);
+        store.update(&send("after", "1/10")?).await?;
+        assert_eq!(store.touch_token("frozentoken").await?, 2);
+
+        tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+
+        assert_eq!(store.get_all_keys("frozentoken", "").await?.len(), 2);
+        let after = Key::try_from(("frozentoken", "after"))?;
+        let ttl: i64 = store.redis.clone().ttl(after.redis_key("current")).await?;
+        assert_eq!(ttl, -1);
+
+        assert_eq!(store.unfreeze("frozentoken").await?, 2);
+        assert!(!store.is_frozen("frozentoken").await?);
+        let ttl: i64 = store.redis.clone().ttl(after.redis_key("current")).await?;
+        assert!(ttl > 0);
+
+        tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+        assert!(store.get_all_keys("frozentoken", "").await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn lua_and_rust_agree_on_done() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let mut redis = ConnectionManager::new(client).await?;
+        let key = Key::try_from(("donetoken", "build"))?;
+        let script = redis::Script::new(concat!(
+            is_done_lua!(),
+            r"
+local keys = {
+    state = KEYS[1], current = KEYS[2], max = KEYS[3], error = KEYS[4],
+    indeterminate = KEYS[5], reverse = KEYS[6], type = KEYS[7],
+}
+return is_done(keys, tonumber(ARGV[1])) and 1 or 0
+"
+        ));
+
+        let value = |current, max| Value {
+            current,
+            max,
+            ..Value::default()
+        };
+        let cases = [
+            value(Some(10), Some(10)),
+            value(Some(9), Some(10)),
+            value(Some(11), Some(10)),
+            value(Some(0), Some(0)),
+            value(Some(5), None),
+            value(None, Some(10)),
+            Value {
+                error: Some("boom".to_owned()),
+                ..value(Some(10), Some(10))
+            },
+            Value {
+                state: Some("DONE".to_owned()),
+                ..value(Some(1), Some(10))
+            },
+            Value {
+                state: Some("done".to_owned()),
+                error: Some("boom".to_owned()),
+                ..Value::default()
+            },
+            Value {
+                indeterminate: true,
+                ..value(Some(10), Some(10))
+            },
+            Value {
+                metric: Metric::Counter,
+                ..value(Some(10), Some(10))
+            },
+            Value {
+                reverse: true,
+                ..value(Some(0), None)
+            },
+            Value {
+                reverse: true,
+                ..value(Some(3), Some(10))
+            },
+            Value {
+                reverse: true,
+                ..value(Some(0), Some(10))
+            },
+        ];
+
+        let percent = DONE_PERCENT.load(Ordering::Relaxed);
+
+        for case in &cases {
+            let mut pipe = redis::pipe();
+            for (param, v) in VALUE_PARAMS.iter().zip(case.to_redis()) {
+                match v {
+                    Some(v) => pipe.set(key.redis_key(param), v).ignore(),
+                    None => pipe.del(key.redis_key(param)).ignore(),
+                };
+            }
+            pipe.query_async::<_, ()>(&mut redis).await?;
+
+            let mut invocation = script.prepare_invoke();
+            for param in [
+                "state",
+                "current",
+                "max",
+                "error",
+                "indeterminate",
+                "reverse",
+                "type",
+            ] {
+                invocation.key(key.redis_key(param));
+            }
+            let lua: bool = invocation.arg(percent).invoke_async(&mut redis).await?;
+
+            assert_eq!(lua, case.is_done(), "{:?}", case);
+        }
+
+        redis
+            .del::<_, ()>(VALUE_PARAMS.map(|p| key.redis_key(p)).to_vec())
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn completions_are_logged_once() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        for key in store.get_all_keys("eventtoken", "").await? {
+            store.delete_key(&key).await?;
+        }
+        store
+            .redis
+            .clone()
+            .del::<_, ()>(Key::events_key("eventtoken"))
+            .await?;
+
+        let send =
+            |k: &str, v: &str| Update::from_query("eventtoken", (k.to_owned(), v.to_owned()));
+        store.update(&send("build", "5/10")?).await?;
+        assert!(store.get_events("eventtoken").await?.is_empty());
+
+        store
+            .update_many(&[send("build", "10/10")?, send("test", "done!")?])
+            .await?;
+        store.update(&send("build", "done!")?).await?;
+
+        let keys = |events: Vec<CompletionEvent>| Vec::from_iter(events.into_iter().map(|e| e.key));
+        assert_eq!(
+            keys(store.get_events("eventtoken").await?),
+            ["build", "test"]
+        );
+
+        // Rerun, and done again
+        store.update(&send("test", "running!0/10")?).await?;
+        store.update(&send("test", "10/10")?).await?;
+        assert_eq!(
+            keys(store.get_events("eventtoken").await?),
+            ["build", "test", "test"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis at 127.0.0.1"]
+    async fn stale_timestamps_are_ignored() -> Result<()> {
+        let client = redis::Client::open("redis://127.0.0.1/")?;
+        let store = Store::new(ConnectionManager::new(client).await?);
+
+        let key = || Key::try_from(("randomtoken", "skewed"));
+        let update = |current| Update::builder(key().unwrap()).current(current).build();
+
+        assert!(store.update_if_newer(&update(5), 200).await?);
+        assert!(!store.update_if_newer(&update(3), 100).await?);
+        assert_eq!(store.get_state(&key()?).await?.current, Some(5));
+
+        assert!(store.update_if_newer(&update(7), 200).await?);
+        assert_eq!(store.get_state(&key()?).await?.current, Some(7));
+
+        // Plain sends keep the last ts alive along with the rest of the key
+        let short = Update::builder(key()?).current(8).build().with_ttl(Some(1));
+        assert!(store.update_if_newer(&short, 300).await?);
+        store.update(&update(9).with_ttl(Some(60))).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(!store.update_if_newer(&update(4), 250).await?);
+        assert_eq!(store.get_state(&key()?).await?.current, Some(9));
 
         Ok(())
     }